@@ -0,0 +1,89 @@
+use crate::click_mute::port_name;
+use crate::config::{glob_match, Connect};
+use jack::PortSpec;
+
+// Notifications passes the ids of the ports registered by other clients on to AutoConnect, as
+// Jack doesn't allow making connections in its callbacks
+pub struct Notifications {
+    new_ports: crossbeam_channel::Sender<jack::PortId>,
+}
+
+impl Notifications {
+    pub fn new(new_ports: crossbeam_channel::Sender<jack::PortId>) -> Notifications {
+        Notifications { new_ports }
+    }
+}
+
+impl jack::NotificationHandler for Notifications {
+    fn port_registration(&mut self, _: &jack::Client, port_id: jack::PortId, is_registered: bool) {
+        if is_registered {
+            let _ = self.new_ports.send(port_id);
+        }
+    }
+}
+
+// AutoConnect connects the ports of click_mute to the ports matching the configured patterns
+pub struct AutoConnect {
+    connect: Connect,
+}
+
+impl AutoConnect {
+    pub fn new(connect: Connect) -> AutoConnect {
+        AutoConnect { connect }
+    }
+
+    // Connects the matching ports that exist already, such as when activating the client
+    pub fn connect_existing(&self, client: &jack::Client) {
+        let audio = jack::AudioIn::default();
+        let audio_type = audio.jack_port_type();
+        for name in client.ports(None, Some(audio_type), jack::PortFlags::empty()) {
+            if let Some(port) = client.port_by_name(&name) {
+                self.connect_port(client, &port);
+            }
+        }
+    }
+
+    // Connects a port that has been registered after activating the client, if it matches
+    pub fn port_registered(&self, client: &jack::Client, port_id: jack::PortId) {
+        if let Some(port) = client.port_by_id(port_id) {
+            self.connect_port(client, &port);
+        }
+    }
+
+    fn connect_port(&self, client: &jack::Client, port: &jack::Port<jack::Unowned>) {
+        let audio = jack::AudioIn::default();
+        let audio_type = audio.jack_port_type();
+        if client.is_mine(port) || port.port_type().ok().as_deref() != Some(audio_type) {
+            return;
+        }
+        let name = match port.name() {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+        let own_port =
+            |prefix, channel| format!("{}:{}", client.name(), port_name(prefix, channel));
+        let flags = port.flags();
+        if flags.contains(jack::PortFlags::IS_OUTPUT) {
+            for (channel, pattern) in self.connect.inputs.iter().enumerate() {
+                if glob_match(pattern, &name) {
+                    connect_ports(client, &name, &own_port("in", channel));
+                }
+            }
+        }
+        if flags.contains(jack::PortFlags::IS_INPUT) {
+            for (channel, pattern) in self.connect.outputs.iter().enumerate() {
+                if glob_match(pattern, &name) {
+                    connect_ports(client, &own_port("out", channel), &name);
+                }
+            }
+        }
+    }
+}
+
+fn connect_ports(client: &jack::Client, source: &str, destination: &str) {
+    match client.connect_ports_by_name(source, destination) {
+        Ok(()) => println!("Connected {} to {}", source, destination),
+        Err(jack::Error::PortAlreadyConnected(_, _)) => (),
+        Err(error) => eprintln!("Failed to connect {} to {}: {}", source, destination, error),
+    }
+}
@@ -1,93 +1,326 @@
+use crate::comfort_noise::{ClipSpectrum, SpectrumAnalyzer};
 use crate::sampler::Sampler;
+use crate::voice_activity::VoiceActivity;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use thiserror::Error;
 
 #[derive(Clone)]
 pub struct Clip {
-    pub sample_a: Sampler,
-    pub sample_b: Sampler,
+    pub channels: Vec<Sampler>, // one sampler per channel
     pub rms: f32,
+    pub spectrum: ClipSpectrum,
 }
 
 type ClipId = usize;
 
+// Clips louder than the noise floor by more than this (6 dB) are not background noise
+const MAX_CLIP_TO_FLOOR_RATIO: f32 = 2.0;
+
+// The clips are saved in a little-endian binary file:
+//
+//   MAGIC, version (u32), sample rate (u32), number of channels (u32), number of clips (u32)
+//
+// followed by each clip, oldest first, as its length in samples (u32) and the samples (f32) of
+// each channel in turn.
+const MAGIC: &[u8; 8] = b"CMBGCLIP";
+const VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("not a background clip file")]
+    InvalidFormat,
+
+    #[error("the clips are for {num_channels} channels at {sample_rate} Hz")]
+    Mismatch {
+        sample_rate: usize,
+        num_channels: usize,
+    },
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+
+    #[error(transparent)]
+    AtomicIOError(#[from] atomicwrites::Error<io::Error>),
+}
+
+// A file that ends too early isn't a background clip file
+fn read_exact(reader: &mut impl Read, buffer: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buffer).map_err(|error| {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            Error::InvalidFormat
+        } else {
+            Error::IOError(error)
+        }
+    })
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<usize, Error> {
+    let mut bytes = [0u8; 4];
+    read_exact(reader, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes) as usize)
+}
+
 pub struct BackgroundSampler {
+    sample_rate: usize,
+    num_channels: usize,
     current_clip: Option<Clip>,
     clip_length: usize,
     num_clips: usize,
     clip_id_gen: ClipId,
     clips: BTreeMap<ClipId, Clip>,
+    by_rms: Vec<ClipId>,        // the clips, the quietest first
+    analyzer: SpectrumAnalyzer, // analyzes the current clip
+    generation: usize,          // changes whenever the clips change
     rng: StdRng,
+    voice_activity: VoiceActivity,
 }
 
-pub type StereoSample = (f32, f32);
-
 // BackgroundSampler periodically (or maybe randomly) samples short samples, keeping at most num_clips latest
 // ones. Then it orders the samples by their volume and the user may pick n quietest samples from them to use as
 // background noise.  If sampling a sample is interrupted by a pause, then that sample is discarded.
+// Likewise samples are discarded when there is speech, and samples are kept only when they are
+// close to the noise floor, which rises slowly when the room gets louder, so the kept samples
+// follow it.
 impl BackgroundSampler {
-    pub fn new(num_clips: usize, clip_length: usize) -> BackgroundSampler {
+    pub fn new(
+        sample_rate: usize,
+        num_channels: usize,
+        num_clips: usize,
+        clip_length: usize,
+    ) -> BackgroundSampler {
         let mut bg_sampler = BackgroundSampler {
+            sample_rate,
+            num_channels,
             current_clip: None,
             clip_length,
             num_clips,
             clip_id_gen: 0,
             clips: BTreeMap::new(),
+            by_rms: Vec::with_capacity(num_clips + 1),
+            analyzer: SpectrumAnalyzer::new(num_channels),
+            generation: 0,
             rng: StdRng::from_entropy(),
+            voice_activity: VoiceActivity::new(sample_rate),
         };
         bg_sampler.resume();
         bg_sampler
     }
 
+    pub fn set_num_clips(&mut self, num_clips: usize) {
+        self.num_clips = num_clips;
+        self.by_rms
+            .reserve((num_clips + 1).saturating_sub(self.by_rms.len()));
+        while self.clips.len() > self.num_clips {
+            // we could do: self.clips.pop_first();
+            // but let's avoid unstable features for now
+            if let Some((&key, _)) = self.clips.iter().next() {
+                self.clips.remove(&key);
+                self.by_rms.retain(|x| *x != key);
+                self.generation += 1;
+            }
+        }
+    }
+
+    // Adds the clip as the newest one
+    fn insert_clip(&mut self, clip: Clip) {
+        let id = self.new_clip_id();
+        let clips = &self.clips;
+        let index = self.by_rms.partition_point(|x| clips[x].rms <= clip.rms);
+        self.by_rms.insert(index, id);
+        self.clips.insert(id, clip);
+    }
+
+    // Clips of the old length are kept until they are replaced
+    pub fn set_clip_length(&mut self, clip_length: usize) {
+        if clip_length != self.clip_length {
+            self.clip_length = clip_length;
+            if self.current_clip.is_some() {
+                self.current_clip = None;
+                self.resume();
+            }
+        }
+    }
+
     fn new_clip_id(&mut self) -> usize {
         let id = self.clip_id_gen;
         self.clip_id_gen += 1;
         id
     }
 
-    pub fn sample(&mut self, sample: StereoSample) {
+    // Samples one frame, with a sample for each channel
+    pub fn sample(&mut self, frame: &[f32]) {
+        // the noise floor isn't followed during pauses, which are the clicks
+        self.voice_activity
+            .sample(frame, self.current_clip.is_some());
+        let speech = self.voice_activity.is_speech();
         let full = match self.current_clip {
             None => false,
+            Some(ref mut clip) if speech => {
+                // start over after the speech
+                clip.channels.iter_mut().for_each(|x| x.clear());
+                clip.spectrum.clear();
+                self.analyzer.clear();
+                false
+            }
             Some(ref mut clip) => {
-                clip.sample_a.sample(sample.0);
-                clip.sample_b.sample(sample.1);
-                clip.sample_a.is_full()
+                for (sampler, sample) in clip.channels.iter_mut().zip(frame.iter()) {
+                    sampler.sample(*sample);
+                }
+                self.analyzer.sample(frame, &mut clip.spectrum);
+                clip.channels[0].is_full()
             }
         };
         if full {
-            let id = self.new_clip_id();
             let mut clip = self.current_clip.take().unwrap();
-            clip.rms = clip.sample_a.rms().max(clip.sample_b.rms());
-            self.clips.insert(id, clip);
-            if self.clips.len() > self.num_clips {
-                // we could do: self.clips.pop_first();
-                // but let's avoid unstable features for now
-                if let Some((&key, _)) = self.clips.iter().next() {
-                    self.clips.remove(&key);
-                }
+            clip.rms = clip.channels.iter().map(|x| x.rms()).fold(0.0, f32::max);
+            let near_floor = match self.voice_activity.noise_floor() {
+                Some(floor) => clip.rms <= floor * MAX_CLIP_TO_FLOOR_RATIO,
+                None => true,
+            };
+            if near_floor {
+                self.insert_clip(clip);
+                self.generation += 1;
             }
+            self.set_num_clips(self.num_clips);
             self.resume();
         }
     }
 
+    // The RMS levels of the clips, the oldest first
+    pub fn clips_rms(&self) -> impl Iterator<Item = f32> + '_ {
+        self.clips.values().map(|x| x.rms)
+    }
+
+    // The RMS level of the noise floor, once it's known
+    pub fn noise_floor(&self) -> Option<f32> {
+        self.voice_activity.noise_floor()
+    }
+
+    // the n least-rms clips, the quietest first
+    pub fn quietest_clips(&self, limit: usize) -> impl Iterator<Item = &Clip> + '_ {
+        self.by_rms
+            .iter()
+            .take(limit)
+            .map(move |id| &self.clips[id])
+    }
+
     // pick a random clip from the n least-rms clips
     pub fn choose_clip(&mut self, limit: usize) -> Option<Clip> {
-        if self.clips.is_empty() {
+        let num_clips = limit.min(self.clips.len());
+        if num_clips == 0 {
             None
         } else {
-            let mut clips: Vec<&Clip> = self.clips.iter().map(|(_, v)| v).collect();
-            clips.sort_unstable_by(|a, b| {
-                if a.rms < b.rms {
-                    std::cmp::Ordering::Less
-                } else if a.rms > b.rms {
-                    std::cmp::Ordering::Greater
-                } else {
-                    std::cmp::Ordering::Equal
-                }
+            let index = self.rng.gen_range(0..num_clips);
+            Some(self.clips[&self.by_rms[index]].clone())
+        }
+    }
+
+    // Changes whenever the clips change
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn clear(&mut self) {
+        self.clips.clear();
+        self.by_rms.clear();
+        self.generation += 1;
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), Error> {
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        for value in [
+            VERSION,
+            self.sample_rate as u32,
+            self.num_channels as u32,
+            self.clips.len() as u32,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for clip in self.clips.values() {
+            let channels: Vec<Vec<f32>> = clip.channels.iter().map(|x| x.get()).collect();
+            data.extend_from_slice(&(channels[0].len() as u32).to_le_bytes());
+            for sample in channels.iter().flatten() {
+                data.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        let writer = atomicwrites::AtomicFile::new(filename, atomicwrites::AllowOverwrite);
+        writer.write(|f| f.write_all(&data))?;
+        Ok(())
+    }
+
+    /** Loads the clips saved with save, as the oldest clips, and returns how many there were. The
+     * clips need to have been sampled with the same sample rate and number of channels. */
+    pub fn load(&mut self, filename: &str) -> Result<usize, Error> {
+        let data = fs::read(filename)?;
+        // what is left of the data after reading from it, for checking the sizes read against it
+        // before allocating anything for them
+        let mut reader: &[u8] = &data;
+        let mut magic = [0u8; MAGIC.len()];
+        read_exact(&mut reader, &mut magic)?;
+        if magic != *MAGIC || read_u32(&mut reader)? != VERSION as usize {
+            return Err(Error::InvalidFormat);
+        }
+        let sample_rate = read_u32(&mut reader)?;
+        let num_channels = read_u32(&mut reader)?;
+        if sample_rate != self.sample_rate || num_channels != self.num_channels {
+            return Err(Error::Mismatch {
+                sample_rate,
+                num_channels,
             });
-            Some(clips[self.rng.gen_range(0..limit.min(clips.len()))].clone())
         }
+        let num_clips = read_u32(&mut reader)?;
+        // each clip has at least its length
+        if num_clips > reader.len() / 4 {
+            return Err(Error::InvalidFormat);
+        }
+        let mut clips = Vec::with_capacity(num_clips);
+        let mut analyzer = SpectrumAnalyzer::new(num_channels);
+        for _ in 0..num_clips {
+            let length = read_u32(&mut reader)?;
+            if length > reader.len() / 4 / num_channels.max(1) {
+                return Err(Error::InvalidFormat);
+            }
+            let mut clip = Clip {
+                channels: Vec::with_capacity(num_channels),
+                rms: 0.0,
+                spectrum: ClipSpectrum::new(num_channels),
+            };
+            for _ in 0..num_channels {
+                // a sampler holds one sample less than its size
+                let mut sampler = Sampler::new(length + 1, true);
+                for _ in 0..length {
+                    let mut bytes = [0u8; 4];
+                    read_exact(&mut reader, &mut bytes)?;
+                    sampler.sample(f32::from_le_bytes(bytes));
+                }
+                clip.channels.push(sampler);
+            }
+            clip.rms = clip.channels.iter().map(|x| x.rms()).fold(0.0, f32::max);
+            let samples: Vec<Vec<f32>> = clip.channels.iter().map(|x| x.get()).collect();
+            analyzer.clear();
+            for index in 0..length {
+                let frame: Vec<f32> = samples.iter().map(|x| x[index]).collect();
+                analyzer.sample(&frame, &mut clip.spectrum);
+            }
+            clips.push(clip);
+        }
+
+        // the clips loaded go before the ones sampled so far
+        let sampled: Vec<Clip> = self.clips.values().cloned().collect();
+        self.clips.clear();
+        self.by_rms.clear();
+        for clip in clips.into_iter().chain(sampled.into_iter()) {
+            self.insert_clip(clip);
+        }
+        self.generation += 1;
+        self.set_num_clips(self.num_clips);
+        Ok(num_clips)
     }
 
     pub fn pause(&mut self) {
@@ -97,10 +330,117 @@ impl BackgroundSampler {
     pub fn resume(&mut self) {
         if self.current_clip.is_none() {
             self.current_clip = Some(Clip {
-                sample_a: Sampler::new(self.clip_length, true),
-                sample_b: Sampler::new(self.clip_length, true),
+                channels: (0..self.num_channels)
+                    .map(|_| Sampler::new(self.clip_length, true))
+                    .collect(),
                 rms: 0.0,
+                spectrum: ClipSpectrum::new(self.num_channels),
             });
+            self.analyzer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const SAMPLE_RATE: usize = 48000;
+    const NUM_CHANNELS: usize = 2;
+
+    // A file in the temporary directory, removed when dropped
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path = env::temp_dir().join(format!("click_mute_{}_{}", std::process::id(), name));
+            TempFile(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn sampler_with_clips(lengths: &[usize]) -> BackgroundSampler {
+        let mut bg_sampler = BackgroundSampler::new(SAMPLE_RATE, NUM_CHANNELS, 10, 1000);
+        for (index, length) in lengths.iter().enumerate() {
+            let mut clip = Clip {
+                channels: vec![],
+                rms: 0.0,
+                spectrum: ClipSpectrum::new(NUM_CHANNELS),
+            };
+            for channel in 0..NUM_CHANNELS {
+                let mut sampler = Sampler::new(length + 1, true);
+                for x in 0..*length {
+                    sampler.sample(((x * (channel + 1) + index) % 7) as f32 / 10.0);
+                }
+                clip.channels.push(sampler);
+            }
+            clip.rms = clip.channels.iter().map(|x| x.rms()).fold(0.0, f32::max);
+            bg_sampler.insert_clip(clip);
+        }
+        bg_sampler
+    }
+
+    fn clip_samples(bg_sampler: &BackgroundSampler) -> Vec<Vec<Vec<f32>>> {
+        bg_sampler
+            .clips
+            .values()
+            .map(|clip| clip.channels.iter().map(|x| x.get()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn save_and_load() {
+        let file = TempFile::new("save_and_load");
+        let saved = sampler_with_clips(&[100, 0, 600]);
+        saved.save(&file.0).unwrap();
+        let mut loaded = BackgroundSampler::new(SAMPLE_RATE, NUM_CHANNELS, 10, 1000);
+        assert_eq!(loaded.load(&file.0).unwrap(), 3);
+        assert_eq!(clip_samples(&loaded), clip_samples(&saved));
+        assert_eq!(
+            loaded.clips_rms().collect::<Vec<f32>>(),
+            saved.clips_rms().collect::<Vec<f32>>()
+        );
+    }
+
+    #[test]
+    fn load_mismatch() {
+        let file = TempFile::new("load_mismatch");
+        sampler_with_clips(&[100]).save(&file.0).unwrap();
+        let mut loaded = BackgroundSampler::new(SAMPLE_RATE, 1, 10, 1000);
+        assert!(matches!(loaded.load(&file.0), Err(Error::Mismatch { .. })));
+    }
+
+    #[test]
+    fn load_truncated() {
+        let file = TempFile::new("load_truncated");
+        sampler_with_clips(&[100, 200]).save(&file.0).unwrap();
+        let data = fs::read(&file.0).unwrap();
+        for length in [0, 4, MAGIC.len() + 8, MAGIC.len() + 20, data.len() - 1] {
+            fs::write(&file.0, &data[..length]).unwrap();
+            let mut loaded = BackgroundSampler::new(SAMPLE_RATE, NUM_CHANNELS, 10, 1000);
+            assert!(matches!(loaded.load(&file.0), Err(Error::InvalidFormat)));
+            assert_eq!(loaded.clips.len(), 0);
+        }
+    }
+
+    #[test]
+    fn load_too_large_sizes() {
+        let file = TempFile::new("load_too_large_sizes");
+        sampler_with_clips(&[100]).save(&file.0).unwrap();
+        let data = fs::read(&file.0).unwrap();
+        // the number of clips, and then the length of the clip
+        for offset in [MAGIC.len() + 12, MAGIC.len() + 16] {
+            let mut corrupt = data.clone();
+            corrupt[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            fs::write(&file.0, &corrupt).unwrap();
+            let mut loaded = BackgroundSampler::new(SAMPLE_RATE, NUM_CHANNELS, 10, 1000);
+            assert!(matches!(loaded.load(&file.0), Err(Error::InvalidFormat)));
         }
     }
 }
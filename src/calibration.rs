@@ -0,0 +1,216 @@
+use crate::config::Delays;
+
+// How much audio around each click is captured, in seconds relative to the click
+const PRE_SECONDS: f64 = 0.2;
+const POST_SECONDS: f64 = 0.3;
+
+// At most this many clicks are captured
+const MAX_CAPTURES: usize = 200;
+
+// Clicks without a transient at least this many times the noise floor are ignored
+const MIN_PEAK_TO_NOISE: f32 = 4.0;
+
+// The transient starts and ends where it crosses this fraction between the noise floor and the peak
+const THRESHOLD_FRACTION: f32 = 0.1;
+
+// Calibration captures the undelayed audio around clicks while the user types in silence, and then
+// finds out when the click sounds start and end in relation to the events, to propose Delays.
+pub struct Calibration {
+    sample_rate: usize,
+    duration_samples: usize,
+    pre_samples: usize,
+    post_samples: usize,
+
+    // audio history, so that captures can start before the click was reported
+    history: Vec<f32>,
+    history_index: usize, // next write position
+
+    sample_index: usize,
+    num_clicks: usize,
+    // sample indices of the clicks whose capture is not yet complete
+    pending: Vec<usize>,
+    // captured windows of pre_samples + post_samples each, one after another
+    captures: Vec<f32>,
+}
+
+// Mean and standard deviation of a set of measurements
+#[derive(Debug, Clone, Copy)]
+pub struct Statistics {
+    pub mean: f64,
+    pub std_dev: f64,
+}
+
+pub struct Proposal {
+    pub delays: Delays,
+    pub onset: Statistics, // seconds relative to the click
+    pub end: Statistics,   // seconds relative to the click
+    pub num_used: usize,   // how many of the captures had a transient
+    pub num_captures: usize,
+}
+
+impl Statistics {
+    fn new(values: &[f64]) -> Statistics {
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / values.len() as f64;
+        Statistics {
+            mean,
+            std_dev: variance.sqrt(),
+        }
+    }
+}
+
+// The value below which the given fraction of the values are
+fn percentile(values: &[f64], fraction: f64) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    sorted[usize::min(sorted.len() - 1, (fraction * sorted.len() as f64) as usize)]
+}
+
+impl Calibration {
+    pub fn new(sample_rate: usize, duration_seconds: f64) -> Calibration {
+        let pre_samples = (PRE_SECONDS * sample_rate as f64) as usize;
+        let post_samples = (POST_SECONDS * sample_rate as f64) as usize;
+        // clicks can be reported a bit late, so keep a bit more history than a single window
+        let history_len = pre_samples + post_samples + sample_rate / 4;
+        Calibration {
+            sample_rate,
+            duration_samples: (duration_seconds * sample_rate as f64) as usize,
+            pre_samples,
+            post_samples,
+            history: vec![0.0; history_len],
+            history_index: 0,
+            sample_index: 0,
+            num_clicks: 0,
+            pending: Vec::with_capacity(MAX_CAPTURES),
+            captures: Vec::with_capacity(MAX_CAPTURES * (pre_samples + post_samples)),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.sample_index >= self.duration_samples
+    }
+
+    // Progress from 0 to 1
+    pub fn progress(&self) -> f32 {
+        f32::min(1.0, self.sample_index as f32 / self.duration_samples as f32)
+    }
+
+    pub fn num_clicks(&self) -> usize {
+        self.num_clicks
+    }
+
+    pub fn num_captures(&self) -> usize {
+        self.captures.len() / (self.pre_samples + self.post_samples)
+    }
+
+    // Called for each click, delta being the click time in seconds relative to the next sample
+    pub fn click(&mut self, delta: f64) {
+        if self.is_finished() {
+            return;
+        }
+        self.num_clicks += 1;
+        let delta_samples = (-delta * self.sample_rate as f64) as usize;
+        // captures that would need more history than we have are skipped, as are clicks after we
+        // have enough of them
+        if delta_samples + self.pre_samples <= self.sample_index
+            && delta_samples + self.pre_samples + self.post_samples <= self.history.len()
+            && self.pending.len() + self.num_captures() < MAX_CAPTURES
+        {
+            // keep pending in order, so the first one is always the next one to complete
+            let click_index = self.sample_index - delta_samples;
+            let position = self.pending.partition_point(|x| *x <= click_index);
+            self.pending.insert(position, click_index);
+        }
+    }
+
+    pub fn sample(&mut self, sample: f32) {
+        if self.is_finished() {
+            return;
+        }
+        self.history[self.history_index] = sample;
+        self.history_index = (self.history_index + 1) % self.history.len();
+        self.sample_index += 1;
+
+        let window_len = self.pre_samples + self.post_samples;
+        while let Some(&click_index) = self.pending.first() {
+            if click_index + self.post_samples > self.sample_index {
+                break;
+            }
+            // history_index is where the sample at sample_index will be written
+            let history_len = self.history.len();
+            let age = self.sample_index - (click_index - self.pre_samples);
+            let start = (self.history_index + history_len - age) % history_len;
+            for offset in 0..window_len {
+                self.captures
+                    .push(self.history[(start + offset) % history_len]);
+            }
+            self.pending.remove(0);
+        }
+    }
+
+    // Finds the onset and the end of the transient in a capture, in samples from its beginning
+    fn find_transient(window: &[f32]) -> Option<(usize, usize)> {
+        // a simple envelope: the absolute value with a fast attack and a slow release
+        let mut envelope = Vec::with_capacity(window.len());
+        let mut level = 0.0f32;
+        for sample in window {
+            level = f32::max(sample.abs(), level * 0.995);
+            envelope.push(level);
+        }
+        let mut sorted = envelope.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let noise = sorted[sorted.len() / 5];
+        let (peak_index, peak) =
+            envelope
+                .iter()
+                .enumerate()
+                .fold((0, 0.0f32), |(max_index, max), (index, x)| {
+                    if *x > max {
+                        (index, *x)
+                    } else {
+                        (max_index, max)
+                    }
+                });
+        if peak < noise * MIN_PEAK_TO_NOISE || peak <= 0.0 {
+            return None;
+        }
+        let threshold = noise + (peak - noise) * THRESHOLD_FRACTION;
+        let onset = envelope[0..peak_index]
+            .iter()
+            .rposition(|x| *x < threshold)
+            .map_or(0, |x| x + 1);
+        let end = envelope[peak_index..]
+            .iter()
+            .position(|x| *x < threshold)
+            .map_or(envelope.len(), |x| peak_index + x);
+        Some((onset, end))
+    }
+
+    /** Proposes delays covering most of the click sounds captured. fade is kept as it is given
+     * in current, and the mute is started early enough for the fade to complete before the
+     * onset. */
+    pub fn propose(&self, current: &Delays) -> Option<Proposal> {
+        let window_len = self.pre_samples + self.post_samples;
+        let to_seconds =
+            |index: usize| (index as f64 - self.pre_samples as f64) / self.sample_rate as f64;
+        let (onsets, ends): (Vec<f64>, Vec<f64>) = self
+            .captures
+            .chunks(window_len)
+            .filter_map(Self::find_transient)
+            .map(|(onset, end)| (to_seconds(onset), to_seconds(end)))
+            .unzip();
+        if onsets.is_empty() {
+            return None;
+        }
+        let mut delays = *current;
+        delays.mute_offset = percentile(&onsets, 0.1) - current.fade;
+        delays.mute_duration = f64::max(0.0, percentile(&ends, 0.9));
+        Some(Proposal {
+            delays,
+            onset: Statistics::new(&onsets),
+            end: Statistics::new(&ends),
+            num_used: onsets.len(),
+            num_captures: self.num_captures(),
+        })
+    }
+}
@@ -1,3 +1,6 @@
+use crate::calibration::Calibration;
+use crate::config::Config;
+use crate::device_info::DeviceInfo;
 use crate::sampler::Sampler;
 use std::time::Instant;
 
@@ -8,10 +11,26 @@ pub struct ClickInfo {
     pub invert_mute: bool,
     pub background_noise: bool,
     pub num_clicks: usize,
+    // input devices with key events, as last enumerated
+    pub devices: Vec<DeviceInfo>,
+    pub sample_rate: usize,
+    // calibration in progress; ClickMute feeds it until it is finished
+    pub calibration: Option<Calibration>,
+    // the RMS levels of the background clips, the oldest first, and of the noise floor
+    pub background_clips: Vec<f32>,
+    pub noise_floor: Option<f32>,
+    // where the recordings go, the directory of the recording in progress, and the error that
+    // stopped the latest recording
+    pub recording_dir: String,
+    pub recording: Option<String>,
+    pub recording_error: Option<String>,
+    // the configuration as last sent to ClickMute, so that all controllers see the changes
+    // made by each other
+    pub config: Config,
 }
 
 impl ClickInfo {
-    pub fn new() -> ClickInfo {
+    pub fn new(config: Config) -> ClickInfo {
         ClickInfo {
             live_sampler: Sampler::new(10240, true),
             click_sampler: {
@@ -23,6 +42,15 @@ impl ClickInfo {
             invert_mute: false,
             background_noise: true,
             num_clicks: 0,
+            devices: vec![],
+            sample_rate: 48000,
+            calibration: None,
+            background_clips: Vec::with_capacity(config.background.num_clips),
+            noise_floor: None,
+            recording_dir: String::from("recordings"),
+            recording: None,
+            recording_error: None,
+            config,
         }
     }
 }
@@ -1,304 +1,909 @@
-use crate::background_sampler::BackgroundSampler;
+use crate::auto_connect::{AutoConnect, Notifications};
+use crate::background_sampler::{self, BackgroundSampler};
 use crate::click_info::ClickInfo;
 use crate::click_mute_control;
-use crate::config::Config;
+use crate::click_source::{Click, ClickSource};
+use crate::comfort_noise::ComfortNoise;
+use crate::config::{
+    BackgroundSource, Config, Delays, Suppression, TriggerMode, ALIGN_RANGE_SECONDS, MAX_CHANNELS,
+};
+use crate::fade_curve::FadeCurve;
 use crate::level_event::LevelEvent;
 use crate::looper::Looper;
 use crate::measure;
-use crate::save::Save;
-use crate::{clicky_events::ClickyEvents, cross_fader::CrossFader, delay::Delay, fader::Fader};
+use crate::recording::{self, Recorder, Tap};
+use crate::spectral_suppressor::{self, SpectralSuppressor};
+use crate::transient_detector::{self, TransientDetector};
+use crate::{cross_fader::CrossFader, delay::Delay, fader::Fader};
+use std::collections::VecDeque;
+use std::io;
+use std::iter;
 use std::sync::{Arc, Mutex};
+use std::time;
 use thiserror::Error;
 
-struct ClickMute {
-    in_a: jack::Port<jack::AudioIn>,
-    in_b: jack::Port<jack::AudioIn>,
-    out_a: jack::Port<jack::AudioOut>,
-    out_b: jack::Port<jack::AudioOut>,
-    sample_rate: usize,
+// How many hotkey changes can be waiting for the delayed audio to catch up
+const MAX_HOTKEY_CHANGES: usize = 64;
+
+// How many recent transients and armed windows are kept track of
+const MAX_TRANSIENTS: usize = 64;
+
+// How many stopped recordings may wait to be finished outside the Jack thread
+const MAX_RETIRED_RECORDERS: usize = 16;
+
+// How often to check for exiting while waiting for new ports to connect
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+struct Ports {
+    inputs: Vec<jack::Port<jack::AudioIn>>,
+    outputs: Vec<jack::Port<jack::AudioOut>>,
+}
+
+// JackClickMute binds the ClickMute processing to the Jack ports and the click source
+struct JackClickMute {
+    ports: Ports,
+    click_mute: ClickMute,
+    control: click_mute_control::Receiver,
+
+    click_source: Box<dyn ClickSource>,
+    clicks: Vec<Click>, // reused between process calls to avoid allocation
+
+    // the stopped recordings are dropped, and their files finished, in the main thread
+    retired_recorders: crossbeam_channel::Sender<Box<Recorder>>,
+
+    measure_when_clicked: Arc<Mutex<measure::Repeated>>,
+}
+
+// The processing state of a single channel
+struct Channel {
+    // the delay is in two parts, so that the suppressor can be given the samples look_ahead
+    // samples before they are output, and its output comes out in time with them
+    delay: Delay,
+    look_ahead: Delay,
+    fader: Fader,
+    cross_fader: CrossFader,
+    hotkey_fader: Fader, // push-to-talk and push-to-mute
+    suppressor: SpectralSuppressor,
+    suppressed: f32, // the output of the suppressor for the current delayed sample
+}
 
-    delay_seconds: f64,         // how long is the delay buffer
-    mute_offset_seconds: f64,   // how long to wait until we start mute
-    mute_duration_seconds: f64, // how long will the mute last
-    fade_samples: usize,        // how many sample_index will the fade in/fade out last
+// With TriggerMode::Both, the transients between start and end (undelayed sample indices) are
+// muted using the delays of the click that armed the window. When aligning, the first transient
+// is muted instead of the fixed mute window of the click, which is used only if no transient
+// has been found by the time it starts.
+struct ArmedWindow {
+    start: usize,
+    end: usize,
+    delays: Delays,
+    mute_duration: f64,               // of the delays, for the kind of the click
+    fallback: Option<(usize, usize)>, // the fixed mute window, in delayed sample indices
+}
 
-    delay_a: Delay,
-    delay_b: Delay,
+// ClickMute does the actual processing; it has no knowledge of where the audio or the click events
+// come from
+pub struct ClickMute {
+    sample_rate: usize,
 
-    fader_a: Fader,
-    fader_b: Fader,
+    config: Config,     // the delays to use, for all devices
+    delay_seconds: f64, // how long is the delay buffer, including look_ahead
+    look_ahead: usize,  // how many samples before the output the suppressor gets them
+    // how many sample_index will the fade in/fade out of the mute window last, and its curve: the
+    // longest fade of the clicks merged into the window
+    fade_samples: usize,
+    fade_curve: FadeCurve,
 
-    cross_fader_a: CrossFader,
-    cross_fader_b: CrossFader,
+    channels: Vec<Channel>,
+    // the current delayed frame and background noise frame, one sample per channel
+    frame: Vec<f32>,
+    background_frame: Vec<f32>,
 
-    clicky_events: Arc<Mutex<ClickyEvents>>,
+    hotkey_muted: bool,
+    // sample indices at which the hotkey state changes, along with the new state
+    hotkey_changes: VecDeque<(usize, bool)>,
 
     sample_index: usize,
 
     mute_t0_index: Option<usize>,
     mute_t1_index: usize,
+    muted: bool, // between mute_t0_index and mute_t1_index
+    // like mute_t0_index and muted, but look_ahead samples ahead, for the suppressor
+    suppress_t0_index: Option<usize>,
+    suppressing: bool,
+
+    transient_detector: TransientDetector, // looks at the undelayed input
+    // the undelayed sample indices of the recent transients, for the clicks arriving after them
+    transients: VecDeque<usize>,
+    armed_windows: VecDeque<ArmedWindow>,
 
     click_info: Arc<Mutex<ClickInfo>>,
-    control: click_mute_control::Receiver,
 
-    save: Option<(Save, Save, Save, Save, bool)>,
+    recorder: Option<Box<Recorder>>,
 
     background_sampler: BackgroundSampler,
     background_looper: Looper,
+    comfort_noise: ComfortNoise,
+    // the generation of the clips when they were last reported to the ClickInfo
+    reported_clips: Option<usize>,
 
-    measure_when_clicked: Arc<Mutex<measure::Repeated>>,
+    events: Option<click_mute_control::EventSender>,
 }
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     JackError(#[from] jack::Error),
+
+    #[error(transparent)]
+    RecordingError(#[from] recording::Error),
 }
 
-impl ClickMute {
+// Ports are named by the channel letter, such as in_a and out_a for the first channel
+pub fn port_name(prefix: &str, channel: usize) -> String {
+    format!("{}_{}", prefix, (b'a' + channel as u8) as char)
+}
+
+impl Ports {
+    fn new(client: &jack::Client, num_channels: usize) -> Result<Ports, Error> {
+        let mut ports = Ports {
+            inputs: Vec::with_capacity(num_channels),
+            outputs: Vec::with_capacity(num_channels),
+        };
+        for channel in 0..num_channels {
+            ports
+                .inputs
+                .push(client.register_port(&port_name("in", channel), jack::AudioIn::default())?);
+        }
+        for channel in 0..num_channels {
+            ports
+                .outputs
+                .push(client.register_port(&port_name("out", channel), jack::AudioOut::default())?);
+        }
+        Ok(ports)
+    }
+}
+
+impl JackClickMute {
     fn new(
         client: &jack::Client,
         click_info: Arc<Mutex<ClickInfo>>,
         config: Config,
         control: click_mute_control::Receiver,
+        click_source: Box<dyn ClickSource>,
+        events: Option<click_mute_control::EventSender>,
+        retired_recorders: crossbeam_channel::Sender<Box<Recorder>>,
+    ) -> Result<JackClickMute, Error> {
+        Ok(JackClickMute {
+            ports: Ports::new(client, config.channels)?,
+            click_mute: ClickMute::new(client.sample_rate(), click_info, config, events),
+            control,
+            click_source,
+            clicks: Vec::with_capacity(64),
+            retired_recorders,
+            measure_when_clicked: Arc::new(Mutex::new(measure::Repeated::new())),
+        })
+    }
+
+    fn stop(&mut self) {
+        self.click_source.stop()
+    }
+
+    // Passes the recorder to be dropped outside the Jack thread, unless too many are waiting
+    fn retire(&self, recorder: Option<Box<Recorder>>) {
+        if let Some(recorder) = recorder {
+            let _ = self.retired_recorders.try_send(recorder);
+        }
+    }
+
+    fn process_control(&mut self) {
+        match self.control.try_recv() {
+            Ok(click_mute_control::Message::UpdateConfig(config)) => {
+                self.click_source.update_config(&config);
+                self.click_mute.update_config(*config);
+            }
+            Ok(click_mute_control::Message::ClearBackground) => self.click_mute.clear_background(),
+            Ok(click_mute_control::Message::StartRecording(recorder)) => {
+                let old_recorder = self.click_mute.start_recording(recorder);
+                self.retire(old_recorder);
+            }
+            Ok(click_mute_control::Message::StopRecording) => {
+                let recorder = self.click_mute.stop_recording();
+                self.retire(recorder);
+            }
+            Err(_) => (),
+        }
+    }
+
+    fn process(&mut self, ps: &jack::ProcessScope) -> jack::Control {
+        self.process_control();
+
+        // arrays instead of vectors, to avoid allocation
+        let num_channels = self.ports.inputs.len();
+        let mut inputs: [&[f32]; MAX_CHANNELS] = Default::default();
+        let mut outputs: [&mut [f32]; MAX_CHANNELS] = Default::default();
+        for (input, port) in inputs.iter_mut().zip(self.ports.inputs.iter()) {
+            *input = port.as_slice(ps);
+        }
+        for (output, port) in outputs.iter_mut().zip(self.ports.outputs.iter_mut()) {
+            *output = port.as_mut_slice(ps);
+        }
+
+        let mut measure_when_clicked = self.measure_when_clicked.lock().unwrap();
+        let click_source = &mut self.click_source;
+        let clicks = &mut self.clicks;
+        clicks.clear();
+
+        measure_when_clicked.measure(move || click_source.when_clicked(clicks));
+
+        if measure_when_clicked.prev_time() > measure_when_clicked.average() * 10 {
+            eprintln!(
+                "Getting clicky events took {:?}, average {:?}",
+                measure_when_clicked.prev_time(),
+                measure_when_clicked.average(),
+            );
+        }
+
+        self.click_mute.process(
+            &self.clicks,
+            self.click_source.is_hotkey_muted(),
+            &inputs[0..num_channels],
+            &mut outputs[0..num_channels],
+        );
+
+        // the writer has reported its error to click_info
+        if self.click_mute.recording_failed() {
+            let recorder = self.click_mute.stop_recording();
+            self.retire(recorder);
+        }
+
+        jack::Control::Continue
+    }
+}
+
+impl Channel {
+    fn new(
+        sample_rate: usize,
+        delay_samples: usize,
+        look_ahead: usize,
+        fade_samples: usize,
+    ) -> Channel {
+        let mut channel = Channel {
+            delay: Delay::new(0),
+            look_ahead: Delay::new(0),
+            fader: Fader::new(0.0),
+            cross_fader: CrossFader::new(0.0),
+            hotkey_fader: Fader::new(0.0),
+            suppressor: SpectralSuppressor::new(sample_rate),
+            suppressed: 0.0,
+        };
+        channel.set_delay(delay_samples, look_ahead);
+        channel.cross_fader.fade_in(fade_samples);
+        channel.fader.fade_in(fade_samples);
+        channel.hotkey_fader.fade_in(fade_samples);
+        channel
+    }
+
+    // delay_samples is the whole delay, which is at least look_ahead
+    fn set_delay(&mut self, delay_samples: usize, look_ahead: usize) {
+        self.delay = Delay::new(delay_samples - look_ahead);
+        self.look_ahead = Delay::new(look_ahead);
+    }
+
+    /** Delays an input sample, and returns the delayed one. With the spectral suppression, the
+     * suppressor is given the samples look_ahead samples early, along with whether they are in a
+     * mute window; it is fed all the time, so that its output is ready when it is needed. */
+    fn delay_sample(&mut self, sample: f32, suppression: Suppression, suppress: bool) -> f32 {
+        let sample = self.delay.process(sample);
+        if suppression == Suppression::Spectral {
+            self.suppressed = self.suppressor.process(sample, suppress);
+        }
+        self.look_ahead.process(sample)
+    }
+
+    // The hotkeys mute completely regardless of the attenuation
+    fn set_attenuation_gain(&mut self, gain: f32) {
+        self.fader.set_floor(gain);
+        self.cross_fader.set_floor(gain);
+        self.suppressor.set_floor(gain);
+    }
+
+    fn set_fade_curve(&mut self, curve: FadeCurve) {
+        self.fader.set_curve(curve);
+        self.cross_fader.set_curve(curve);
+    }
+
+    // Starts the fade at the beginning of the mute window
+    fn start_mute(&mut self, click_info: &ClickInfo, fade_samples: usize, curve: FadeCurve) {
+        self.set_fade_curve(curve);
+        if click_info.invert_mute {
+            self.fader.fade_in(fade_samples);
+        } else if click_info.background_noise {
+            self.cross_fader.fade_out(fade_samples);
+        } else {
+            self.fader.fade_out(fade_samples);
+        }
+    }
+
+    // Starts the fade at the end of the mute window
+    fn end_mute(&mut self, click_info: &ClickInfo, fade_samples: usize, curve: FadeCurve) {
+        self.set_fade_curve(curve);
+        if click_info.invert_mute {
+            self.fader.fade_out(fade_samples);
+        } else if click_info.background_noise {
+            self.cross_fader.fade_in(fade_samples);
+        } else {
+            self.fader.fade_in(fade_samples);
+        }
+    }
+
+    // Applies the muting to a delayed sample
+    fn process(
+        &mut self,
+        click_info: &ClickInfo,
+        suppression: Suppression,
+        sample: f32,
+        background: f32,
+    ) -> f32 {
+        let sample = if click_info.mute_enabled {
+            if suppression == Suppression::Spectral && !click_info.invert_mute {
+                self.suppressed
+            } else if click_info.invert_mute || !click_info.background_noise {
+                self.fader.process(sample)
+            } else {
+                self.cross_fader.process(sample, background)
+            }
+        } else {
+            sample
+        };
+        self.hotkey_fader.process(sample)
+    }
+}
+
+impl ClickMute {
+    pub fn new(
+        sample_rate: usize,
+        click_info: Arc<Mutex<ClickInfo>>,
+        config: Config,
+        events: Option<click_mute_control::EventSender>,
     ) -> ClickMute {
-        let in_a = client
-            .register_port("in_a", jack::AudioIn::default())
-            .unwrap();
-        let in_b: jack::Port<jack::AudioIn> = client
-            .register_port("in_b", jack::AudioIn::default())
-            .unwrap();
-        let out_a = client
-            .register_port("out_a", jack::AudioOut::default())
-            .unwrap();
-        let out_b = client
-            .register_port("out_b", jack::AudioOut::default())
-            .unwrap();
-
-        let mute_offset_seconds = config.delays.mute_offset; // delta from the time we detect an event to until we mute sound (so, negative because we hear it before we get the vent)
-        let delay_seconds = f64::max(0.0, -mute_offset_seconds); // size of the delay buffer in seconds; sensibly just as long as the mute_offset is
-        let mute_duration_seconds = config.delays.mute_duration; // how long do we mute for?
+        let look_ahead = look_ahead(&config);
+        let delay_samples = delay_samples(&config, sample_rate);
         let fade_seconds = config.delays.fade; // how long is the fade in/out to avoid pops?
 
-        let sample_rate = client.sample_rate();
-        let delay_samples = (delay_seconds * sample_rate as f64) as usize;
         let fade_samples = (fade_seconds * sample_rate as f64) as usize;
 
-        let mut cross_fader_a = CrossFader::new(0.0);
-        let mut cross_fader_b = CrossFader::new(0.0);
-        let mut fader_a = Fader::new(0.0);
-        let mut fader_b = Fader::new(0.0);
+        let num_channels = config.channels;
+        let attenuation_gain = config.attenuation_gain();
+        let fade_curve = config.delays.curve;
+        let transient_detector = TransientDetector::new(sample_rate, config.trigger.threshold);
+        let num_clips = config.background.num_clips;
+        let clip_length = config.background.clip_length(sample_rate);
+        let overlap = config.background.overlap(sample_rate);
 
-        cross_fader_a.fade_in(fade_samples);
-        cross_fader_b.fade_in(fade_samples);
-        fader_a.fade_in(fade_samples);
-        fader_b.fade_in(fade_samples);
+        click_info.lock().unwrap().sample_rate = sample_rate;
 
         ClickMute {
-            in_a,
-            in_b,
-            out_a,
-            out_b,
             sample_rate,
 
-            delay_seconds,
-            mute_offset_seconds,
-            mute_duration_seconds,
+            config,
+            delay_seconds: delay_samples as f64 / sample_rate as f64,
+            look_ahead,
             fade_samples,
-            delay_a: Delay::new(usize::max(1, delay_samples)),
-            delay_b: Delay::new(usize::max(1, delay_samples)),
-
-            fader_a,
-            fader_b,
-            cross_fader_a,
-            cross_fader_b,
-
-            clicky_events: Arc::new(Mutex::new(ClickyEvents::new())),
+            fade_curve,
+            channels: (0..num_channels)
+                .map(|_| {
+                    let mut channel =
+                        Channel::new(sample_rate, delay_samples, look_ahead, fade_samples);
+                    channel.set_attenuation_gain(attenuation_gain);
+                    channel
+                })
+                .collect(),
+            frame: vec![0.0; num_channels],
+            background_frame: vec![0.0; num_channels],
+
+            hotkey_muted: false,
+            hotkey_changes: VecDeque::with_capacity(MAX_HOTKEY_CHANGES),
 
             sample_index: 0,
             mute_t0_index: None,
             mute_t1_index: 0,
+            muted: false,
+            suppress_t0_index: None,
+            suppressing: false,
+
+            transient_detector,
+            transients: VecDeque::with_capacity(MAX_TRANSIENTS),
+            armed_windows: VecDeque::with_capacity(MAX_TRANSIENTS),
 
             click_info,
-            control,
 
-            save: None,
-            // save: Some((
-            //     Save::new(1, "0.wav"),
-            //     Save::new(1, "1.wav"),
-            //     Save::new(1, "2.wav"),
-            //     Save::new(1, "3.wav"),
-            //     false,
-            // )),
-            background_sampler: BackgroundSampler::new(20, 1024),
-            background_looper: Looper::new(),
+            recorder: None,
+            background_sampler: BackgroundSampler::new(
+                sample_rate,
+                num_channels,
+                num_clips,
+                clip_length,
+            ),
+            background_looper: Looper::new(overlap),
+            comfort_noise: ComfortNoise::new(num_channels),
+            reported_clips: None,
+
+            events,
+        }
+    }
 
-            measure_when_clicked: Arc::new(Mutex::new(measure::Repeated::new())),
+    pub fn update_config(&mut self, config: Config) {
+        // TODO: remove duplicate code by just moving complete ClickMute construction here?
+        let look_ahead = look_ahead(&config);
+        let delay_samples = delay_samples(&config, self.sample_rate);
+
+        // the number of channels stays as it was, as the ports can't be changed
+        self.config = Config {
+            channels: self.channels.len(),
+            ..config
+        };
+        self.delay_seconds = delay_samples as f64 / self.sample_rate as f64;
+        self.look_ahead = look_ahead;
+        let attenuation_gain = self.config.attenuation_gain();
+        self.transient_detector
+            .set_threshold(self.config.trigger.threshold);
+        let background = self.config.background;
+        self.background_sampler.set_num_clips(background.num_clips);
+        self.background_sampler
+            .set_clip_length(background.clip_length(self.sample_rate));
+        self.background_looper
+            .set_overlap(background.overlap(self.sample_rate));
+        self.reported_clips = None;
+        for channel in self.channels.iter_mut() {
+            channel.set_delay(delay_samples, look_ahead);
+            channel.set_attenuation_gain(attenuation_gain);
         }
     }
 
-    fn stop(&mut self) {
-        self.clicky_events.lock().unwrap().stop()
+    // How many samples the output lags behind the input
+    pub fn delay_samples(&self) -> usize {
+        (self.delay_seconds * self.sample_rate as f64).round() as usize
     }
 
-    fn update_config(&mut self, config: Config) {
-        // TODO: remove duplicate code by just moving complete ClickMute construction here?
-        let mute_offset_seconds = config.delays.mute_offset; // delta from the time we detect an event to until we mute sound (so, negative because we hear it before we get the vent)
-        let delay_seconds = f64::max(0.0, -mute_offset_seconds); // size of the delay buffer in seconds; sensibly just as long as the mute_offset is
-        let mute_duration_seconds = config.delays.mute_duration; // how long do we mute for?
-        let fade_seconds = config.delays.fade; // how long is the fade in/out to avoid pops?
+    // Returns the previous Recorder, if any, stopped like with stop_recording
+    pub fn start_recording(&mut self, recorder: Box<Recorder>) -> Option<Box<Recorder>> {
+        let old_recorder = self.stop_recording();
+        self.recorder = Some(recorder);
+        old_recorder
+    }
 
-        let delay_samples = (delay_seconds * self.sample_rate as f64) as usize;
-        let fade_samples = (fade_seconds * self.sample_rate as f64) as usize;
+    // The recording is finished when the returned Recorder is dropped, which is better done
+    // outside the realtime thread
+    pub fn stop_recording(&mut self) -> Option<Box<Recorder>> {
+        let mut recorder = self.recorder.take()?;
+        recorder.finish();
+        Some(recorder)
+    }
 
-        self.delay_seconds = delay_seconds;
-        self.mute_offset_seconds = mute_offset_seconds;
-        self.mute_duration_seconds = mute_duration_seconds;
-        self.fade_samples = fade_samples;
-        self.delay_a = Delay::new(usize::max(1, delay_samples));
-        self.delay_b = Delay::new(usize::max(1, delay_samples));
+    // Whether the writer of the recording has stopped, so the recording should be stopped
+    pub fn recording_failed(&self) -> bool {
+        self.recorder.as_ref().map_or(false, |x| x.failed())
     }
 
-    fn process_control(&mut self) {
-        if let Ok(click_mute_control::Message::UpdateConfig(config)) = self.control.try_recv() {
-            self.update_config(config);
+    pub fn clear_background(&mut self) {
+        self.background_sampler.clear();
+    }
+
+    // Loads the background clips saved on the previous run, if there are any
+    pub fn load_background(&mut self, filename: &str) {
+        match self.background_sampler.load(filename) {
+            Ok(num_clips) => println!("Loaded {} background clips from {}", num_clips, filename),
+            Err(background_sampler::Error::IOError(error))
+                if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => println!("Ignoring background clips in {}: {}", filename, error),
         }
     }
 
-    fn process(&mut self, ps: &jack::ProcessScope) -> jack::Control {
-        self.process_control();
+    pub fn save_background(&self, filename: &str) {
+        if let Err(error) = self.background_sampler.save(filename) {
+            println!("Failed to save background clips to {}: {}", filename, error);
+        }
+    }
 
-        let in_a = self.in_a.as_slice(ps);
-        let in_b = self.in_b.as_slice(ps);
-        let out_a = self.out_a.as_mut_slice(ps);
-        let out_b = self.out_b.as_mut_slice(ps);
+    fn send_event(&self, event: click_mute_control::Event) {
+        if let Some(ref events) = self.events {
+            // if nobody is keeping up with the events, they are just dropped
+            let _ = events.try_send(event);
+        }
+    }
 
-        let mut measure_when_clicked = self.measure_when_clicked.lock().unwrap();
-        let mut clicky_events = self.clicky_events.lock().unwrap();
+    fn seconds_to_samples(&self, seconds: f64) -> usize {
+        f64::max(0.0, seconds * self.sample_rate as f64) as usize
+    }
 
-        match measure_when_clicked.measure(move || clicky_events.when_clicked()) {
-            None => (),
-            Some((t0, t1)) => {
-                let mute_wait_seconds = self.delay_seconds + t0 + self.mute_offset_seconds;
-                if self.mute_t0_index == None {
-                    let mute_t0_index = self.sample_index
-                        + f64::max(0.0, mute_wait_seconds * self.sample_rate as f64) as usize;
-                    self.mute_t0_index = Some(mute_t0_index);
-                }
+    // Schedules muting between the delayed sample indices t0 and t1, merging it with the pending
+    // mute window; a window ending before it starts is just the fades
+    fn schedule_mute(&mut self, mute_t0_index: usize, mute_t1_index: usize, delays: &Delays) {
+        let mute_t1_index = usize::max(mute_t0_index, mute_t1_index);
+        let fade_samples = self.seconds_to_samples(delays.fade);
+        let merging = self.mute_t0_index.is_some() || self.muted;
+        if !merging || fade_samples > self.fade_samples {
+            self.fade_samples = fade_samples;
+            self.fade_curve = delays.curve;
+        }
+        self.mute_t0_index = Some(match self.mute_t0_index {
+            None => mute_t0_index,
+            Some(pending_t0_index) => usize::min(pending_t0_index, mute_t0_index),
+        });
+        self.suppress_t0_index = Some(match self.suppress_t0_index {
+            None => mute_t0_index,
+            Some(pending_t0_index) => usize::min(pending_t0_index, mute_t0_index),
+        });
+        self.mute_t1_index = usize::max(self.mute_t1_index, mute_t1_index);
+    }
 
-                self.mute_t1_index = self.sample_index
-                    + ((self.delay_seconds + self.mute_duration_seconds + t1)
-                        * self.sample_rate as f64) as usize;
+    // Schedules muting for a transient at the undelayed sample index, so that the fade is done by
+    // the time of the transient, and the muting lasts for mute_duration after it
+    fn mute_transient(&mut self, index: usize, delays: &Delays, mute_duration: f64) {
+        let delayed_index = index + self.seconds_to_samples(self.delay_seconds);
+        let mute_t0_index = usize::max(
+            self.sample_index,
+            delayed_index.saturating_sub(self.seconds_to_samples(delays.fade)),
+        );
+        let mute_t1_index = usize::max(
+            mute_t0_index,
+            delayed_index + self.seconds_to_samples(mute_duration),
+        );
+        self.schedule_mute(mute_t0_index, mute_t1_index, delays);
+    }
 
-                // here was a debug message for outputting mute indices
-                assert!(Some(self.mute_t1_index) >= self.mute_t0_index);
-                let mut click_info = self.click_info.lock().unwrap();
-                click_info.num_clicks += 1;
+    // Handles a transient detected at the undelayed sample index; returns true if it is counted as
+    // a click
+    fn transient(&mut self, index: usize) -> bool {
+        if self.config.trigger.mode == TriggerMode::Audio {
+            let delays = self.config.delays;
+            self.mute_transient(index, &delays, delays.mute_duration);
+            return true;
+        }
+        if self.transients.len() == self.transients.capacity() {
+            self.transients.pop_front();
+        }
+        self.transients.push_back(index);
+        let position = self
+            .armed_windows
+            .iter()
+            .position(|x| (x.start..=x.end).contains(&index));
+        if let Some(position) = position {
+            let delays = self.armed_windows[position].delays;
+            let mute_duration = self.armed_windows[position].mute_duration;
+            // the aligned window is done with its transient, so its fixed window isn't needed
+            if self.armed_windows[position].fallback.is_some() {
+                self.armed_windows.remove(position);
             }
+            self.mute_transient(index, &delays, mute_duration);
         }
+        // with the evdev mode the click has been counted already
+        position.is_some() && self.config.trigger.mode == TriggerMode::Both
+    }
 
-        if measure_when_clicked.prev_time() > measure_when_clicked.average() * 10 {
-            eprintln!(
-                "Getting clicky events took {:?}, average {:?}",
-                measure_when_clicked.prev_time(),
-                measure_when_clicked.average(),
-            );
+    /** Arms a window around the expected time of the click for the transients, including the
+     * ones already detected, starting early_seconds before the mute window of the click; returns
+     * true if one of them is muted. When aligning, fallback is the fixed mute window of the
+     * click. */
+    fn arm_window(
+        &mut self,
+        click: &Click,
+        delays: &Delays,
+        early_seconds: f64,
+        fallback: Option<(usize, usize)>,
+    ) -> bool {
+        let click_seconds = self.sample_index as f64 / self.sample_rate as f64 + click.delta;
+        let mute_duration = delays.mute_duration_for(click.kind);
+        let window = ArmedWindow {
+            start: self.seconds_to_samples(
+                click_seconds + delays.mute_offset_for(click.kind) - early_seconds,
+            ),
+            end: self.seconds_to_samples(click_seconds + mute_duration),
+            delays: *delays,
+            mute_duration,
+            fallback,
+        };
+        let transient = self
+            .transients
+            .iter()
+            .copied()
+            .find(|x| (window.start..=window.end).contains(x));
+        if let Some(index) = transient {
+            self.mute_transient(index, delays, mute_duration);
+        }
+        let sample_index = self.sample_index;
+        // the windows waiting for their fixed windows to start are kept until then
+        self.armed_windows
+            .retain(|x| x.end >= sample_index || x.fallback.is_some());
+        if transient.is_some() && window.fallback.is_some() {
+            // aligned already, so the window is done
+            return true;
         }
+        if self.armed_windows.len() < self.armed_windows.capacity() {
+            self.armed_windows.push_back(window);
+        } else if let Some((t0, t1)) = window.fallback {
+            // no room to wait for a transient
+            self.schedule_mute(t0, t1, delays);
+        }
+        transient.is_some()
+    }
 
-        let mut click_info = self.click_info.lock().unwrap();
+    // Schedules the fixed mute windows of the aligned clicks that have found no transients by the
+    // time the windows start
+    fn schedule_fallbacks(&mut self) {
+        let sample_index = self.sample_index;
+        while let Some(position) = self
+            .armed_windows
+            .iter()
+            .position(|x| x.fallback.map_or(false, |(t0, _)| t0 <= sample_index))
+        {
+            let window = self.armed_windows.remove(position).unwrap();
+            if let Some((t0, t1)) = window.fallback {
+                self.schedule_mute(usize::max(t0, sample_index), t1, &window.delays);
+            }
+        }
+    }
 
-        for (((in_a, in_b), out_a), out_b) in (in_a.iter())
-            .zip(in_b.iter())
-            .zip(out_a.iter_mut())
-            .zip(out_b.iter_mut())
+    /** Processes one block of audio, with a slice of samples for each channel in inputs and
+     * outputs. clicks are the clicks reported by the ClickSource at the beginning of the block,
+     * and hotkey_muted tells if the hotkeys mute the output. */
+    pub fn process(
+        &mut self,
+        clicks: &[Click],
+        hotkey_muted: bool,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+    ) {
+        // the hotkey applies to the audio from the time it was pressed, which is still in the
+        // delay buffer
+        if hotkey_muted != self.hotkey_muted
+            && self.hotkey_changes.len() < self.hotkey_changes.capacity()
         {
+            self.hotkey_muted = hotkey_muted;
+            // ..but at the start, such as when push-to-talk is in use, apply it right away
+            let delay_samples = if self.sample_index == 0 {
+                0
+            } else {
+                (self.delay_seconds * self.sample_rate as f64) as usize
+            };
+            self.hotkey_changes
+                .push_back((self.sample_index + delay_samples, hotkey_muted));
+        }
+
+        let mut clicked = false;
+        for click in clicks {
+            if !self.config.classes.get(click.class).enabled {
+                continue;
+            }
+            self.send_event(click_mute_control::Event::Click {
+                class: click.class,
+                kind: click.kind,
+            });
+            // each device and class, and presses and releases, may have their own delays
+            let delays = *self
+                .config
+                .click_delays(click.class, click.device.as_deref());
+            // with the audio triggers, only the clicks muting a transient are counted
+            clicked = match self.config.trigger.mode {
+                TriggerMode::Evdev => {
+                    let mute_wait_seconds =
+                        self.delay_seconds + click.delta + delays.mute_offset_for(click.kind);
+                    let mute_end_seconds =
+                        self.delay_seconds + click.delta + delays.mute_duration_for(click.kind);
+                    let fixed_window = (
+                        self.sample_index + self.seconds_to_samples(mute_wait_seconds),
+                        self.sample_index + self.seconds_to_samples(mute_end_seconds),
+                    );
+                    // when aligning, the fixed window is only used if no transient is found
+                    // before it starts
+                    if self.config.trigger.align {
+                        self.arm_window(click, &delays, ALIGN_RANGE_SECONDS, Some(fixed_window));
+                    } else {
+                        self.schedule_mute(fixed_window.0, fixed_window.1, &delays);
+                    }
+                    true
+                }
+                TriggerMode::Audio => false,
+                TriggerMode::Both => self.arm_window(click, &delays, 0.0, None),
+            } || clicked;
+        }
+        if clicked {
+            let mut click_info = self.click_info.lock().unwrap();
+            click_info.num_clicks += 1;
+        }
+
+        // cloned so that self can be modified while the lock is held
+        let click_info = self.click_info.clone();
+        let mut click_info = click_info.lock().unwrap();
+
+        if let Some(ref mut calibration) = click_info.calibration {
+            for click in clicks {
+                if self.config.classes.get(click.class).enabled {
+                    calibration.click(click.delta);
+                }
+            }
+        }
+
+        let num_frames = inputs.iter().map(|x| x.len()).min().unwrap_or(0);
+        for frame_index in 0..num_frames {
+            let mono = inputs.iter().map(|x| x[frame_index]).sum::<f32>() / inputs.len() as f32;
+            if self.config.trigger.uses_transients() && self.transient_detector.sample(mono) {
+                let latency_samples = self.seconds_to_samples(transient_detector::LATENCY_SECONDS);
+                if self.transient(self.sample_index.saturating_sub(latency_samples)) {
+                    click_info.num_clicks += 1;
+                }
+            }
+            self.schedule_fallbacks();
+
             if Some(self.sample_index) == self.mute_t0_index {
-                if click_info.invert_mute {
-                    self.fader_a.fade_in(self.fade_samples);
-                    self.fader_b.fade_in(self.fade_samples);
-                } else if click_info.background_noise {
-                    self.cross_fader_a.fade_out(self.fade_samples);
-                    self.cross_fader_b.fade_out(self.fade_samples);
-                } else {
-                    self.fader_a.fade_out(self.fade_samples);
-                    self.fader_b.fade_out(self.fade_samples);
+                for channel in self.channels.iter_mut() {
+                    channel.start_mute(&click_info, self.fade_samples, self.fade_curve);
                 }
                 self.mute_t0_index = None;
+                if !self.muted {
+                    self.muted = true;
+                    self.send_event(click_mute_control::Event::Mute { muted: true });
+                }
                 click_info.click_sampler.trigger();
-                self.save.iter_mut().for_each(|x| x.4 = !x.4);
                 self.background_sampler.pause();
             }
 
-            if let Some(ref mut save) = self.save {
-                save.0.process(*in_a);
-            };
-
-            let a = self.delay_a.process(*in_a);
-            let b = self.delay_b.process(*in_b);
-            self.background_sampler.sample((a, b));
-
-            self.save.iter_mut().for_each(|x| x.1.process(a));
+            // the window may have been scheduled to start less than look_ahead samples ahead,
+            // in which case the suppression starts late
+            let look_ahead_index = self.sample_index + self.look_ahead;
+            if self
+                .suppress_t0_index
+                .map_or(false, |x| x <= look_ahead_index)
+            {
+                self.suppress_t0_index = None;
+                self.suppressing = true;
+            }
+            for ((sample, channel), input) in self
+                .frame
+                .iter_mut()
+                .zip(self.channels.iter_mut())
+                .zip(inputs.iter())
+            {
+                *sample = channel.delay_sample(
+                    input[frame_index],
+                    self.config.suppression,
+                    self.suppressing,
+                );
+            }
+            if self.suppress_t0_index.is_none() && look_ahead_index >= self.mute_t1_index {
+                self.suppressing = false;
+            }
+            self.background_sampler.sample(&self.frame);
+            if self.reported_clips != Some(self.background_sampler.generation()) {
+                self.reported_clips = Some(self.background_sampler.generation());
+                click_info.background_clips.clear();
+                click_info
+                    .background_clips
+                    .extend(self.background_sampler.clips_rms());
+            }
+            click_info.noise_floor = self.background_sampler.noise_floor();
 
-            click_info.live_sampler.sample(*in_a); // undelayed sample
-            click_info.click_sampler.sample(a); // delayed sample
+            click_info.live_sampler.sample(inputs[0][frame_index]); // undelayed sample
+            if let Some(ref mut calibration) = click_info.calibration {
+                calibration.sample(mono);
+            }
+            click_info.click_sampler.sample(self.frame[0]); // delayed sample
+
+            match self.config.background.source {
+                BackgroundSource::Loop => self.background_looper.produce(
+                    &mut self.background_sampler,
+                    self.config.background.num_quietest,
+                    &mut self.background_frame,
+                ),
+                BackgroundSource::ComfortNoise => self.comfort_noise.produce(
+                    &self.background_sampler,
+                    self.config.background.num_quietest,
+                    &mut self.background_frame,
+                ),
+            }
 
-            let (bg_a, bg_b) = self.background_looper.produce(&mut self.background_sampler);
-            let (a, b) = if click_info.mute_enabled {
-                if click_info.invert_mute || !click_info.background_noise {
-                    (self.fader_a.process(a), self.fader_b.process(b))
-                } else {
-                    (
-                        self.cross_fader_a.process(a, bg_a),
-                        self.cross_fader_b.process(b, bg_b),
-                    )
+            while let Some(&(index, muted)) = self.hotkey_changes.front() {
+                if index > self.sample_index {
+                    break;
                 }
-            } else {
-                (a, b)
-            };
-            self.save.iter_mut().for_each(|x| x.2.process(a));
+                let fade_samples = (self.config.delays.fade * self.sample_rate as f64) as usize;
+                for channel in self.channels.iter_mut() {
+                    channel.hotkey_fader.set_curve(self.config.delays.curve);
+                    if muted {
+                        channel.hotkey_fader.fade_out(fade_samples);
+                    } else {
+                        channel.hotkey_fader.fade_in(fade_samples);
+                    }
+                }
+                self.hotkey_changes.pop_front();
+            }
 
-            self.save
+            for (((channel, output), sample), background) in self
+                .channels
                 .iter_mut()
-                .for_each(|x| x.3.process(if x.4 { 1.0 } else { 0.0 }));
+                .zip(outputs.iter_mut())
+                .zip(self.frame.iter())
+                .zip(self.background_frame.iter())
+            {
+                output[frame_index] =
+                    channel.process(&click_info, self.config.suppression, *sample, *background);
+            }
 
-            *out_a = a;
-            *out_b = b;
+            if let Some(ref mut recorder) = self.recorder {
+                recorder.record(Tap::Input, inputs.iter().map(|x| x[frame_index]));
+                recorder.record(Tap::Delayed, self.frame.iter().copied());
+                recorder.record(Tap::Output, outputs.iter().map(|x| x[frame_index]));
+                recorder.record(Tap::Gate, iter::once(if self.muted { 1.0 } else { 0.0 }));
+                recorder.record(Tap::Background, self.background_frame.iter().copied());
+            }
 
             if self.sample_index == self.mute_t1_index {
-                if click_info.invert_mute {
-                    self.fader_a.fade_out(self.fade_samples);
-                    self.fader_b.fade_out(self.fade_samples);
-                } else if click_info.background_noise {
-                    self.cross_fader_a.fade_in(self.fade_samples);
-                    self.cross_fader_b.fade_in(self.fade_samples);
-                } else {
-                    self.fader_a.fade_in(self.fade_samples);
-                    self.fader_b.fade_in(self.fade_samples);
+                for channel in self.channels.iter_mut() {
+                    channel.end_mute(&click_info, self.fade_samples, self.fade_curve);
                 }
                 if !click_info.click_sampler.is_empty() {
                     click_info.click_sampler.hold_or_auto_hold();
                 }
                 self.background_sampler.resume();
+                if self.muted {
+                    self.muted = false;
+                    self.send_event(click_mute_control::Event::Mute { muted: false });
+                }
             }
 
             self.sample_index += 1
         }
+    }
+}
 
-        jack::Control::Continue
+// How many samples ahead of the output the suppressor needs to know about the mute windows
+fn look_ahead(config: &Config) -> usize {
+    match config.suppression {
+        Suppression::Mute => 0,
+        Suppression::Spectral => spectral_suppressor::FRAME_SIZE,
     }
 }
 
+// The length of the delay buffer: sensibly just as long as the most negative mute_offset is, and
+// the look-ahead of the suppressor on top of that
+fn delay_samples(config: &Config, sample_rate: usize) -> usize {
+    (config.max_delay() * sample_rate as f64) as usize + look_ahead(config)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn main(
     exit: LevelEvent,
     click_info: Arc<Mutex<ClickInfo>>,
     config: Config,
     control: click_mute_control::Receiver,
+    click_source: Box<dyn ClickSource>,
+    events: Option<click_mute_control::EventSender>,
+    background_file: Option<String>,
+    record: Option<Vec<Tap>>,
 ) -> Result<(), Error> {
     let (client, _status) = jack::Client::new("click_mute", jack::ClientOptions::NO_START_SERVER)?;
-
-    let mute = Arc::new(Mutex::new(Some(ClickMute::new(
-        &client, click_info, config, control,
-    ))));
+    let auto_connect = AutoConnect::new(config.connect.clone());
+
+    let (retired_recorders_sender, retired_recorders) =
+        crossbeam_channel::bounded(MAX_RETIRED_RECORDERS);
+    let mut jack_click_mute = JackClickMute::new(
+        &client,
+        click_info.clone(),
+        config,
+        control,
+        click_source,
+        events,
+        retired_recorders_sender,
+    )?;
+    if let Some(ref filename) = background_file {
+        jack_click_mute.click_mute.load_background(filename);
+    }
+    if let Some(taps) = record {
+        let recorder = recording::start(&click_info, &taps)?;
+        jack_click_mute
+            .click_mute
+            .start_recording(Box::new(recorder));
+    }
+    let mute = Arc::new(Mutex::new(Some(jack_click_mute)));
 
     let process = jack::ClosureProcessHandler::new({
         let mute = mute.clone();
@@ -314,16 +919,36 @@ pub fn main(
         }
     });
 
-    let active_client = client.activate_async((), process)?;
+    let (new_ports_sender, new_ports) = crossbeam_channel::unbounded();
+    let active_client = client.activate_async(Notifications::new(new_ports_sender), process)?;
 
     // TODO: handle jack errors
-    exit.wait();
+    auto_connect.connect_existing(active_client.as_client());
+    while !exit.test() {
+        if let Ok(port_id) = new_ports.recv_timeout(POLL_INTERVAL) {
+            auto_connect.port_registered(active_client.as_client(), port_id);
+        }
+        for recorder in retired_recorders.try_iter() {
+            recorder.wait();
+        }
+    }
 
     active_client.deactivate()?;
+    for recorder in retired_recorders.try_iter() {
+        recorder.wait();
+    }
 
     if let Ok(mut x) = mute.lock() {
         match &mut *x {
-            Some(click_mute) => click_mute.stop(),
+            Some(click_mute) => {
+                click_mute.stop();
+                if let Some(recorder) = click_mute.click_mute.stop_recording() {
+                    recorder.wait();
+                }
+                if let Some(ref filename) = background_file {
+                    click_mute.click_mute.save_background(filename);
+                }
+            }
             None => (),
         }
     };
@@ -1,8 +1,26 @@
+use crate::click_source::{ClickClass, ClickKind};
+use serde_derive::Serialize;
 use std::sync::mpsc;
 
 pub enum Message {
-    UpdateConfig(crate::config::Config),
+    UpdateConfig(Box<crate::config::Config>),
+    ClearBackground, // forgets the background clips sampled so far
+    StartRecording(Box<crate::recording::Recorder>),
+    StopRecording,
 }
 
 pub type Receiver = mpsc::Receiver<Message>;
 pub type Sender = mpsc::Sender<Message>;
+
+// Events reported by ClickMute as they happen, such as to the control socket subscribers
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Click { class: ClickClass, kind: ClickKind },
+    Mute { muted: bool },
+}
+
+// Sent from the Jack processing thread, so the channel is bounded and events are dropped if the
+// receiver does not keep up
+pub type EventReceiver = crossbeam_channel::Receiver<Event>;
+pub type EventSender = crossbeam_channel::Sender<Event>;
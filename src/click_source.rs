@@ -0,0 +1,168 @@
+use crate::config::Config;
+use crate::device_info::DeviceInfo;
+use serde_derive::Serialize;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClickKind {
+    Press,
+    Release,
+}
+
+impl ClickKind {
+    // From the value of an evdev key event; None for other values, such as key repeat
+    pub fn from_key_value(value: i32) -> Option<ClickKind> {
+        match value {
+            1 => Some(ClickKind::Press),
+            0 => Some(ClickKind::Release),
+            _ => None,
+        }
+    }
+}
+
+// evdev event types and codes needed for classifying events
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+const BTN_MISC: u16 = 0x100;
+const BTN_LEFT: u16 = 0x110;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_DIGI: u16 = 0x140;
+const BTN_WHEEL: u16 = 0x150;
+const KEY_OK: u16 = 0x160;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+
+// What kind of an input produced the click; each class can be enabled and timed separately
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClickClass {
+    Keyboard, // keyboard keys
+    Mouse,    // mouse buttons, and other buttons such as joystick buttons
+    Wheel,    // scroll wheel ticks
+    Touchpad, // the buttons of touchpads, such as pressing down a clickpad
+}
+
+impl ClickClass {
+    /** Classifies an evdev event from a device that is a touchpad or not; None for events that
+     * are not clicks of any class. Touching a touchpad or a tablet is not a click: those events
+     * come with every touch, such as when moving the pointer. Tapping a touchpad isn't a click
+     * either, as the taps are made up by libinput from the touches and can't be told apart from
+     * them at this level. */
+    pub fn classify(
+        kind: u16,
+        code: u16,
+        value: i32,
+        touchpad: bool,
+    ) -> Option<(ClickClass, ClickKind)> {
+        match kind {
+            EV_KEY => {
+                let class = if !(BTN_MISC..KEY_OK).contains(&code) {
+                    ClickClass::Keyboard
+                } else if (BTN_DIGI..BTN_WHEEL).contains(&code) {
+                    // touches and tools, such as BTN_TOUCH and BTN_TOOL_FINGER
+                    return None;
+                } else if touchpad && (BTN_LEFT..=BTN_MIDDLE).contains(&code) {
+                    ClickClass::Touchpad
+                } else {
+                    // mouse buttons (BTN_MOUSE..BTN_JOYSTICK), and also the other buttons
+                    ClickClass::Mouse
+                };
+                ClickKind::from_key_value(value).map(|kind| (class, kind))
+            }
+            // each wheel event is a tick, or a few; the high resolution versions of these come
+            // along with them, so ignore those
+            EV_REL if code == REL_WHEEL || code == REL_HWHEEL => {
+                Some((ClickClass::Wheel, ClickKind::Press))
+            }
+            _ => None,
+        }
+    }
+}
+
+// A single click reported by a ClickSource
+#[derive(Debug, Clone)]
+pub struct Click {
+    pub class: ClickClass,
+    pub kind: ClickKind,
+    // when the click happened, in seconds relative to the current time; so zero or negative
+    pub delta: f64,
+    // the device the click came from, if known
+    pub device: Option<Arc<DeviceInfo>>,
+}
+
+// ClickSource provides ClickMute the information about when the clicks happened. ClickyEvents
+// reading the input devices is the usual implementation.
+pub trait ClickSource: Send {
+    /** Appends the clicks that have occurred since the previous call to clicks. Called from the
+     * Jack processing thread, so this should not block. */
+    fn when_clicked(&mut self, clicks: &mut Vec<Click>);
+
+    // Whether the push-to-talk or push-to-mute hotkeys currently mute the output
+    fn is_hotkey_muted(&self) -> bool {
+        false
+    }
+
+    // Called when the configuration is changed by the user
+    fn update_config(&mut self, _config: &Config) {}
+
+    // Stop any background activity of the source; called when ClickMute is shutting down
+    fn stop(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EV_ABS: u16 = 0x03;
+    const KEY_A: u16 = 30;
+    const BTN_RIGHT: u16 = 0x111;
+    const BTN_SIDE: u16 = 0x113;
+    const BTN_TOOL_FINGER: u16 = 0x145;
+    const BTN_TOOL_DOUBLETAP: u16 = 0x14d;
+    const BTN_TOUCH: u16 = 0x14a;
+    const REL_X: u16 = 0x00;
+    const REL_WHEEL_HI_RES: u16 = 0x0b;
+
+    #[test]
+    fn classify() {
+        use ClickClass::*;
+        use ClickKind::*;
+        // kind, code, value, touchpad, expected
+        let table = [
+            (EV_KEY, KEY_A, 1, false, Some((Keyboard, Press))),
+            (EV_KEY, KEY_A, 0, false, Some((Keyboard, Release))),
+            (EV_KEY, KEY_A, 2, false, None), // key repeat
+            (EV_KEY, KEY_OK, 1, false, Some((Keyboard, Press))),
+            (EV_KEY, BTN_LEFT, 1, false, Some((Mouse, Press))),
+            (EV_KEY, BTN_RIGHT, 0, false, Some((Mouse, Release))),
+            (EV_KEY, BTN_SIDE, 1, false, Some((Mouse, Press))),
+            (EV_KEY, BTN_MISC, 1, false, Some((Mouse, Press))),
+            (EV_KEY, BTN_LEFT, 1, true, Some((Touchpad, Press))),
+            (EV_KEY, BTN_MIDDLE, 0, true, Some((Touchpad, Release))),
+            (EV_KEY, BTN_SIDE, 1, true, Some((Mouse, Press))),
+            (EV_KEY, BTN_TOUCH, 1, true, None),
+            (EV_KEY, BTN_TOOL_FINGER, 1, true, None),
+            (EV_KEY, BTN_TOOL_DOUBLETAP, 0, true, None),
+            (EV_KEY, BTN_DIGI, 1, false, None),
+            (EV_KEY, BTN_WHEEL, 1, false, Some((Mouse, Press))),
+            (EV_REL, REL_WHEEL, 1, false, Some((Wheel, Press))),
+            (EV_REL, REL_WHEEL, -1, false, Some((Wheel, Press))),
+            (EV_REL, REL_HWHEEL, 1, false, Some((Wheel, Press))),
+            (EV_REL, REL_WHEEL_HI_RES, 120, false, None),
+            (EV_REL, REL_X, 5, false, None),
+            (EV_ABS, 0, 100, true, None),
+        ];
+        for (kind, code, value, touchpad, expected) in table {
+            assert_eq!(
+                ClickClass::classify(kind, code, value, touchpad),
+                expected,
+                "type {:#x} code {:#x} value {} touchpad {}",
+                kind,
+                code,
+                value,
+                touchpad
+            );
+        }
+    }
+}
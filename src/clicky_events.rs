@@ -1,20 +1,160 @@
-use crate::level_event::LevelEvent;
+use crate::click_info::ClickInfo;
+use crate::click_source::{Click, ClickClass, ClickKind, ClickSource, EV_KEY};
+use crate::config;
+use crate::device_info::DeviceInfo;
+use crate::event_log;
+use crate::hotkeys::Hotkeys;
 use std::collections::HashMap;
 use std::ffi::CString;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
 extern crate libc;
 
+// how many events can be waiting for when_clicked before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+
+// how often to look for added or removed devices
+const REENUMERATE_INTERVAL: time::Duration = time::Duration::from_millis(20000);
+
+// epoll user data for the wakeup event; device file descriptors are used as-is
+const WAKEUP_TOKEN: u64 = u64::MAX;
+
+// Messages to the reader thread; sent along with a wakeup
+enum ReaderControl {
+    Stop,
+    UpdateConfig(Box<config::Config>),
+}
+
+// An event read by the reader thread
+struct KeyEvent {
+    time: libc::timeval,
+    class: ClickClass,
+    kind: ClickKind,
+    device: Arc<DeviceInfo>,
+}
+
+// A device being listened to
+struct OpenDevice {
+    device: evdev::Device,
+    info: Arc<DeviceInfo>,
+}
+
 pub struct ClickyEvents {
-    devices: Arc<Mutex<Option<Vec<evdev::Device>>>>,
-    reenumerator_join: Option<thread::JoinHandle<()>>,
-    reenumerator_stop: LevelEvent,
+    events: crossbeam_channel::Receiver<KeyEvent>,
+    reader_join: Option<thread::JoinHandle<()>>,
+    reader_control: crossbeam_channel::Sender<ReaderControl>,
+    reader_wakeup: Arc<EventFd>,
+    hotkey_muted: Arc<AtomicBool>, // updated by the reader thread
+}
+
+// Owned eventfd(2), used for waking up the reader thread from epoll_wait
+struct EventFd {
+    fd: RawFd,
+}
+
+impl EventFd {
+    fn new() -> EventFd {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        assert!(fd >= 0, "eventfd failed");
+        EventFd { fd }
+    }
+
+    fn signal(&self) {
+        let value: u64 = 1;
+        unsafe {
+            libc::write(
+                self.fd,
+                &value as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            );
+        }
+    }
+
+    fn clear(&self) {
+        let mut value: u64 = 0;
+        unsafe {
+            libc::read(
+                self.fd,
+                &mut value as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+// Owned epoll instance
+struct Epoll {
+    fd: RawFd,
 }
 
-fn make_device_mapping(devices: Vec<evdev::Device>) -> HashMap<CString, evdev::Device> {
+impl Epoll {
+    fn new() -> Epoll {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        assert!(fd >= 0, "epoll_create1 failed");
+        Epoll { fd }
+    }
+
+    fn add(&self, fd: RawFd, token: u64) {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        unsafe {
+            libc::epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, fd, &mut event);
+        }
+    }
+
+    fn remove(&self, fd: RawFd) {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        unsafe {
+            libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, &mut event);
+        }
+    }
+
+    // returns the tokens of the ready file descriptors
+    fn wait(&self, timeout: time::Duration) -> Vec<u64> {
+        let mut events: Vec<libc::epoll_event> = vec![libc::epoll_event { events: 0, u64: 0 }; 16];
+        let num_events = unsafe {
+            libc::epoll_wait(
+                self.fd,
+                events.as_mut_ptr(),
+                events.len() as libc::c_int,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if num_events <= 0 {
+            vec![]
+        } else {
+            events[0..num_events as usize]
+                .iter()
+                .map(|x| x.u64)
+                .collect()
+        }
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn make_device_mapping(devices: Vec<OpenDevice>) -> HashMap<CString, OpenDevice> {
     let mut mapping = HashMap::new();
     for device in devices.into_iter() {
-        match device.physical_path() {
+        match device.device.physical_path() {
             None => (), // ignore these, we cannot track them
             Some(ref name) => {
                 mapping.insert(name.clone(), device);
@@ -24,147 +164,291 @@ fn make_device_mapping(devices: Vec<evdev::Device>) -> HashMap<CString, evdev::D
     mapping
 }
 
-fn reenumerator_thread(
-    clicky_devices: Arc<Mutex<Option<Vec<evdev::Device>>>>,
-    reenumerator_stop: LevelEvent,
+// Finds new and removed devices, keeping the old instances of the devices that still exist to
+// avoid repeating events. Devices not enabled in the configuration are handled as if they didn't
+// exist. Devices are added to and removed from epoll as needed.
+fn reenumerate(
+    devices: &mut HashMap<CString, OpenDevice>,
+    epoll: &Epoll,
+    config: &config::Devices,
+    click_info: &Arc<Mutex<ClickInfo>>,
+    first: bool,
 ) {
-    let mut first = true;
-    while if first {
-        true
-    } else {
-        !reenumerator_stop.wait_timeout(time::Duration::from_millis(20000))
-    } {
-        let devices = evdev::enumerate();
-        let mut key_devices: Vec<evdev::Device> = vec![];
-
-        for device in devices {
-            if device.events_supported().contains(evdev::KEY) {
-                key_devices.push(device);
+    let mut key_devices: Vec<OpenDevice> = vec![];
+    let mut device_infos: Vec<DeviceInfo> = vec![];
+
+    for device in evdev::enumerate() {
+        let has_wheel = device.events_supported().contains(evdev::RELATIVE)
+            && device
+                .relative_axes_supported()
+                .intersects(evdev::REL_WHEEL | evdev::REL_HWHEEL);
+        if device.events_supported().contains(evdev::KEY) || has_wheel {
+            let mut device_info = DeviceInfo::new(&device);
+            device_info.enabled = config.is_enabled(&device_info);
+            if device_info.enabled {
+                key_devices.push(OpenDevice {
+                    device,
+                    info: Arc::new(device_info.clone()),
+                });
             }
+            device_infos.push(device_info);
         }
+    }
 
-        if key_devices.is_empty() && first {
-            println!("No devices with key output found; missing permissions to /dev/input?");
+    if device_infos.is_empty() && first {
+        println!("No devices with key or wheel output found; missing permissions to /dev/input?");
+    }
+
+    device_infos.sort_by(|a, b| (&a.name, &a.phys).cmp(&(&b.name, &b.phys)));
+    click_info.lock().unwrap().devices = device_infos;
+
+    let mut new = make_device_mapping(key_devices);
+
+    let removed_keys: Vec<CString> = devices
+        .keys()
+        .filter(|old_key| !new.contains_key(*old_key))
+        .cloned()
+        .collect();
+    for old_key in removed_keys {
+        println!("Device removed: {:?}", old_key);
+        if let Some(device) = devices.remove(&old_key) {
+            epoll.remove(device.device.fd());
         }
+    }
 
-        let mut new = make_device_mapping(key_devices);
-        if let Ok(mut write_devices) = clicky_devices.clone().lock() {
-            // find new and removed devices
-            let device_state = (*write_devices).take().unwrap();
-            let mut old = make_device_mapping(device_state);
+    let added_keys: Vec<CString> = new
+        .keys()
+        .filter(|new_key| !devices.contains_key(*new_key))
+        .cloned()
+        .collect();
+    for new_key in added_keys {
+        println!("Device added: {:?}", new_key);
+        let device = new.remove(&new_key).unwrap();
+        epoll.add(device.device.fd(), device.device.fd() as u64);
+        devices.insert(new_key, device);
+    }
+}
+
+fn now_timeval() -> libc::timeval {
+    let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_REALTIME, &mut now);
+    };
+    libc::timeval {
+        tv_sec: now.tv_sec,
+        tv_usec: now.tv_nsec / 1000,
+    }
+}
+
+// Reads the events from all the key devices as they arrive, and passes them on to when_clicked
+fn reader_thread(
+    events: crossbeam_channel::Sender<KeyEvent>,
+    control: crossbeam_channel::Receiver<ReaderControl>,
+    wakeup: Arc<EventFd>,
+    mut config: config::Config,
+    click_info: Arc<Mutex<ClickInfo>>,
+    mut recorder: Option<event_log::Recorder>,
+    hotkey_muted: Arc<AtomicBool>,
+) {
+    let epoll = Epoll::new();
+    epoll.add(wakeup.fd, WAKEUP_TOKEN);
 
-            let mut new_devices: Vec<evdev::Device> = vec![];
+    let mut hotkeys = Hotkeys::new(&config.hotkeys);
+    hotkey_muted.store(hotkeys.is_muted(), Ordering::Relaxed);
 
-            let mut old_device_keys = vec![];
-            // find out which devices we have in common
-            for old_key in old.keys() {
-                // new set has a device as we know
-                if new.contains_key(old_key) {
-                    // use the old one to avoid repeating events
-                    old_device_keys.push(old_key.clone())
-                } else {
-                    println!("Device removed: {:?}", old_key);
+    let mut devices: HashMap<CString, OpenDevice> = HashMap::new();
+    reenumerate(&mut devices, &epoll, &config.devices, &click_info, true);
+    let mut next_reenumerate = time::Instant::now() + REENUMERATE_INTERVAL;
+
+    loop {
+        let now = time::Instant::now();
+        if now >= next_reenumerate {
+            reenumerate(&mut devices, &epoll, &config.devices, &click_info, false);
+            next_reenumerate = now + REENUMERATE_INTERVAL;
+            continue;
+        }
+
+        let ready = epoll.wait(next_reenumerate - now);
+        if ready.contains(&WAKEUP_TOKEN) {
+            wakeup.clear();
+            let mut stop = false;
+            while let Ok(message) = control.try_recv() {
+                match message {
+                    ReaderControl::Stop => stop = true,
+                    ReaderControl::UpdateConfig(new_config) => {
+                        if new_config.devices != config.devices {
+                            // apply the new configuration right away
+                            next_reenumerate = now;
+                        }
+                        if new_config.hotkeys != config.hotkeys {
+                            // the held keys are forgotten, but they'll be pressed again
+                            hotkeys = Hotkeys::new(&new_config.hotkeys);
+                            hotkey_muted.store(hotkeys.is_muted(), Ordering::Relaxed);
+                        }
+                        config = *new_config;
+                    }
                 }
             }
+            if stop {
+                break;
+            }
+        }
 
-            let mut new_device_keys = vec![];
-            // find out which devices are new
-            for new_key in new.keys() {
-                // new set has a device not known before
-                if !old.contains_key(new_key) {
-                    // so add that to the device list
-                    println!("Device added: {:?}", new_key);
-                    new_device_keys.push(new_key.clone())
-                }
+        let read_time = now_timeval();
+        let mut gone_keys: Vec<CString> = vec![];
+        for (key, OpenDevice { device, info }) in devices.iter_mut() {
+            if !ready.contains(&(device.fd() as u64)) {
+                continue;
             }
-            for old_key in old_device_keys {
-                new_devices.push(old.remove(&old_key).take().unwrap())
+            if let Ok(device_events) = device.events() {
+                for event in device_events {
+                    let is_hotkey =
+                        event._type == EV_KEY && hotkeys.key_event(event.code, event.value);
+                    hotkey_muted.store(hotkeys.is_muted(), Ordering::Relaxed);
+                    // using the hotkeys doesn't cause clicks
+                    let classified = if is_hotkey {
+                        None
+                    } else {
+                        ClickClass::classify(event._type, event.code, event.value, info.touchpad)
+                    };
+                    // all key events are recorded, including key repeats
+                    if event._type != EV_KEY && classified.is_none() {
+                        continue;
+                    }
+                    if let Some(ref mut recorder) = recorder {
+                        recorder.record(
+                            event_log::Event {
+                                time: event_log::timeval_seconds(
+                                    event.time.tv_sec,
+                                    event.time.tv_usec,
+                                ),
+                                kind: event._type,
+                                code: event.code,
+                                value: event.value,
+                                device: Some(info.phys.clone()).filter(|x| !x.is_empty()),
+                                read_time: Some(event_log::timeval_seconds(
+                                    read_time.tv_sec,
+                                    read_time.tv_usec,
+                                )),
+                            },
+                            info,
+                        );
+                    }
+                    if let Some((class, kind)) = classified {
+                        // if when_clicked isn't keeping up, there's no point in queuing more
+                        let _ = events.try_send(KeyEvent {
+                            time: event.time,
+                            class,
+                            kind,
+                            device: info.clone(),
+                        });
+                    }
+                }
+            } else {
+                gone_keys.push(key.clone());
             }
-            for new_key in new_device_keys {
-                new_devices.push(new.remove(&new_key).take().unwrap())
+        }
+        // the devices that failed are likely gone; forget them, so that they are opened again
+        // if they come back at the same path, and re-enumerate the devices shortly
+        for key in gone_keys {
+            if let Some(device) = devices.remove(&key) {
+                println!("Device removed: {:?}", key);
+                epoll.remove(device.device.fd());
             }
-
-            *write_devices = Some(new_devices)
+            next_reenumerate = time::Instant::now() + time::Duration::from_millis(1000);
         }
-
-        first = false;
     }
 }
 
 impl ClickyEvents {
-    pub fn new() -> ClickyEvents {
-        let kbd_devices = Arc::new(Mutex::new(Some(vec![])));
+    pub fn new(
+        config: &config::Config,
+        click_info: Arc<Mutex<ClickInfo>>,
+        recorder: Option<event_log::Recorder>,
+    ) -> ClickyEvents {
+        let (send_events, recv_events) = crossbeam_channel::bounded(QUEUE_SIZE);
+        let (send_control, recv_control) = crossbeam_channel::unbounded();
 
-        let reenumerator_stop = LevelEvent::new();
+        let reader_wakeup = Arc::new(EventFd::new());
+        let hotkey_muted = Arc::new(AtomicBool::new(false));
 
-        let reenumerator_join = Option::Some({
-            let kbd_devices = kbd_devices.clone();
-            let reenumerator_stop = reenumerator_stop.clone();
-            thread::spawn(move || reenumerator_thread(kbd_devices, reenumerator_stop))
+        let reader_join = Option::Some({
+            let reader_wakeup = reader_wakeup.clone();
+            let config = config.clone();
+            let hotkey_muted = hotkey_muted.clone();
+            thread::spawn(move || {
+                reader_thread(
+                    send_events,
+                    recv_control,
+                    reader_wakeup,
+                    config,
+                    click_info,
+                    recorder,
+                    hotkey_muted,
+                )
+            })
         });
 
         ClickyEvents {
-            devices: kbd_devices,
-            reenumerator_join,
-            reenumerator_stop,
+            events: recv_events,
+            reader_join,
+            reader_control: send_control,
+            reader_wakeup,
+            hotkey_muted,
         }
     }
 
-    pub fn stop(&mut self) {
-        self.reenumerator_stop.activate();
-        if let Some(join) = self.reenumerator_join.take() {
+    fn send_reader_control(&self, message: ReaderControl) {
+        // the reader thread only goes away when stopped
+        let _ = self.reader_control.send(message);
+        self.reader_wakeup.signal();
+    }
+}
+
+impl ClickSource for ClickyEvents {
+    fn update_config(&mut self, config: &config::Config) {
+        self.send_reader_control(ReaderControl::UpdateConfig(Box::new(config.clone())));
+    }
+
+    fn is_hotkey_muted(&self) -> bool {
+        self.hotkey_muted.load(Ordering::Relaxed)
+    }
+
+    fn stop(&mut self) {
+        self.send_reader_control(ReaderControl::Stop);
+        if let Some(join) = self.reader_join.take() {
             join.join().unwrap();
         }
     }
 
-    /** If clicked, returns a timespan of two negative numbers indicating in which
-     * time window relative to the current time the events occurred (in seconds) */
-    pub fn when_clicked(&mut self) -> Option<(f64, f64)> {
-        let mut clicked = None;
+    // Called from the Jack processing thread: only drains the queue filled by the reader thread
+    fn when_clicked(&mut self, clicks: &mut Vec<Click>) {
         let mut time_t1: libc::timespec = unsafe { std::mem::zeroed() };
         unsafe {
             libc::clock_gettime(libc::CLOCK_REALTIME, &mut time_t1);
         };
-        if let Ok(mut devices) = self.devices.clone().lock() {
-            let mut local_devices = devices.take().unwrap();
-            for device in &mut local_devices {
-                if let Ok(events) = device.events() {
-                    for event in events {
-                        if ((1_u32) << event._type) & evdev::KEY.bits() != 0
-                            && (event.value == 0 || event.value == 1)
-                        {
-                            let mut usec_delta = time_t1.tv_nsec / 1000 - event.time.tv_usec;
-                            let mut sec_delta = time_t1.tv_sec - event.time.tv_sec;
-                            if usec_delta < 0 {
-                                usec_delta += 1000000;
-                                sec_delta -= 1;
-                            }
-                            let delta =
-                                f64::min(-0.0, -(sec_delta as f64 + usec_delta as f64 / 1000000.0));
-                            if delta < -0.100 {
-                                // https://github.com/eras/ClickMuteJack/issues/6
-                                println!(
-					"Dropped too old event value {} at {}+{} -> delta {} (issue #6)",
-					event.value, sec_delta, usec_delta, delta
-                                    );
-                            } else {
-                                clicked = match clicked {
-                                    None => Some((delta, delta)),
-                                    Some((oldest, newest)) => {
-                                        Some((f64::min(oldest, delta), f64::max(newest, delta)))
-                                    }
-                                };
-                            }
-                        }
-                    }
-                } else {
-                    // actually let's just ignore the error; we will
-                    // re-enumerate the devices shortly
-                }
+        while let Ok(event) = self.events.try_recv() {
+            let mut usec_delta = time_t1.tv_nsec / 1000 - event.time.tv_usec;
+            let mut sec_delta = time_t1.tv_sec - event.time.tv_sec;
+            if usec_delta < 0 {
+                usec_delta += 1000000;
+                sec_delta -= 1;
+            }
+            let delta = f64::min(-0.0, -(sec_delta as f64 + usec_delta as f64 / 1000000.0));
+            if delta < -event_log::MAX_EVENT_AGE {
+                // https://github.com/eras/ClickMuteJack/issues/6
+                println!(
+                    "Dropped too old event {:?} at {}+{} -> delta {} (issue #6)",
+                    event.kind, sec_delta, usec_delta, delta
+                );
+            } else {
+                clicks.push(Click {
+                    class: event.class,
+                    kind: event.kind,
+                    delta,
+                    device: Some(event.device),
+                });
             }
-            // put it back 🙄
-            *devices = Some(local_devices);
         }
-        clicked
     }
 }
@@ -0,0 +1,201 @@
+use crate::background_sampler::BackgroundSampler;
+use crate::fft::{self, Complex, Fft};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f32::consts::PI;
+
+// The length of the frames used for analysis and synthesis, and how often a frame is synthesized
+const FRAME_SIZE: usize = 512;
+const HOP_SIZE: usize = FRAME_SIZE / 4;
+
+// The clips need at least one frame for their spectrum, and the samplers of the clips hold one
+// sample less than their size
+pub const MIN_CLIP_LENGTH: usize = FRAME_SIZE + 1;
+
+// How far apart the frames analyzed from the clips are
+const ANALYSIS_HOP_SIZE: usize = FRAME_SIZE / 2;
+
+// The power spectrum of a clip, summed over its frames, for estimating the noise spectrum
+#[derive(Clone)]
+pub struct ClipSpectrum {
+    power: Vec<Vec<f32>>, // of each channel
+    num_frames: usize,
+}
+
+impl ClipSpectrum {
+    pub fn new(num_channels: usize) -> ClipSpectrum {
+        ClipSpectrum {
+            power: vec![vec![0.0; FRAME_SIZE / 2 + 1]; num_channels],
+            num_frames: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.power.iter_mut().flatten().for_each(|x| *x = 0.0);
+        self.num_frames = 0;
+    }
+}
+
+// SpectrumAnalyzer analyzes the frames of a clip into its ClipSpectrum as the clip is sampled, so
+// the work is spread over the samples instead of being done when the spectrum is needed
+pub struct SpectrumAnalyzer {
+    fft: Fft,
+    window: Vec<f32>,
+    frames: Vec<Vec<f32>>, // ring buffers of the latest FRAME_SIZE samples of each channel
+    position: usize,
+    num_samples: usize, // since clear
+    spectrum: Vec<Complex>,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(num_channels: usize) -> SpectrumAnalyzer {
+        SpectrumAnalyzer {
+            fft: Fft::new(FRAME_SIZE),
+            window: fft::sqrt_hann(FRAME_SIZE),
+            frames: vec![vec![0.0; FRAME_SIZE]; num_channels],
+            position: 0,
+            num_samples: 0,
+            spectrum: vec![Complex::default(); FRAME_SIZE],
+        }
+    }
+
+    // Starts over with a new clip
+    pub fn clear(&mut self) {
+        self.position = 0;
+        self.num_samples = 0;
+    }
+
+    // Samples one frame of the clip, with a sample for each channel
+    pub fn sample(&mut self, frame: &[f32], clip_spectrum: &mut ClipSpectrum) {
+        for (samples, sample) in self.frames.iter_mut().zip(frame.iter()) {
+            samples[self.position] = *sample;
+        }
+        self.position = (self.position + 1) % FRAME_SIZE;
+        self.num_samples += 1;
+        if self.num_samples < FRAME_SIZE || (self.num_samples - FRAME_SIZE) % ANALYSIS_HOP_SIZE != 0
+        {
+            return;
+        }
+        for (samples, power) in self.frames.iter().zip(clip_spectrum.power.iter_mut()) {
+            // the oldest sample is at position
+            for (index, bin) in self.spectrum.iter_mut().enumerate() {
+                let sample = samples[(self.position + index) % FRAME_SIZE];
+                *bin = Complex::new(sample * self.window[index], 0.0);
+            }
+            self.fft.forward(&mut self.spectrum);
+            for (power, bin) in power.iter_mut().zip(self.spectrum.iter()) {
+                *power += bin.re * bin.re + bin.im * bin.im;
+            }
+        }
+        clip_spectrum.num_frames += 1;
+    }
+}
+
+// ComfortNoise synthesizes noise with the power spectrum of the quietest background clips, with
+// random phases, so it doesn't repeat like looping the clips does and speech in the clips is
+// smeared out
+pub struct ComfortNoise {
+    fft: Fft,
+    window: Vec<f32>,
+
+    magnitudes: Vec<Vec<f32>>, // the noise spectrum of each channel
+    power: Vec<Vec<f32>>,      // for summing the spectra of the clips
+    // the generation of the clips and the number of the quietest ones the spectrum was
+    // estimated from
+    estimated_from: Option<(usize, usize)>,
+
+    spectrum: Vec<Complex>,
+    output: Vec<Vec<f32>>, // ring buffers of the overlap-added output of each channel
+    position: usize,
+    hop_position: usize,
+    rng: StdRng,
+}
+
+impl ComfortNoise {
+    pub fn new(num_channels: usize) -> ComfortNoise {
+        ComfortNoise {
+            fft: Fft::new(FRAME_SIZE),
+            window: fft::sqrt_hann(FRAME_SIZE),
+            magnitudes: vec![vec![0.0; FRAME_SIZE / 2 + 1]; num_channels],
+            power: vec![vec![0.0; FRAME_SIZE / 2 + 1]; num_channels],
+            estimated_from: None,
+            spectrum: vec![Complex::default(); FRAME_SIZE],
+            output: vec![vec![0.0; FRAME_SIZE]; num_channels],
+            position: 0,
+            hop_position: 0,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    // Estimates the noise spectrum as the average power spectrum of the frames of the clips,
+    // from the spectra analyzed as the clips were sampled
+    fn estimate(&mut self, background_sampler: &BackgroundSampler, num_quietest: usize) {
+        self.power.iter_mut().flatten().for_each(|x| *x = 0.0);
+        let mut num_frames = 0;
+        for clip in background_sampler.quietest_clips(num_quietest) {
+            for (power, clip_power) in self.power.iter_mut().zip(clip.spectrum.power.iter()) {
+                for (power, clip_power) in power.iter_mut().zip(clip_power.iter()) {
+                    *power += clip_power;
+                }
+            }
+            num_frames += clip.spectrum.num_frames;
+        }
+        for (magnitudes, power) in self.magnitudes.iter_mut().zip(self.power.iter()) {
+            for (magnitude, power) in magnitudes.iter_mut().zip(power.iter()) {
+                *magnitude = if num_frames > 0 {
+                    f32::sqrt(power / num_frames as f32)
+                } else {
+                    0.0
+                };
+            }
+        }
+    }
+
+    // Adds a frame of noise with random phases to the output of each channel
+    fn synthesize_frame(&mut self) {
+        for (magnitudes, output) in self.magnitudes.iter().zip(self.output.iter_mut()) {
+            for (bin, magnitude) in magnitudes.iter().enumerate() {
+                let phase = self.rng.gen_range(0.0..2.0 * PI);
+                self.spectrum[bin] =
+                    Complex::new(f32::cos(phase), f32::sin(phase)).scale(*magnitude);
+                if bin != 0 && bin != FRAME_SIZE / 2 {
+                    // the mirrored bin of a real signal is the complex conjugate
+                    self.spectrum[FRAME_SIZE - bin] =
+                        Complex::new(self.spectrum[bin].re, -self.spectrum[bin].im);
+                }
+            }
+            // the bins without a mirror need to be real
+            self.spectrum[0].im = 0.0;
+            self.spectrum[FRAME_SIZE / 2].im = 0.0;
+            self.fft.inverse(&mut self.spectrum);
+            for (index, bin) in self.spectrum.iter().enumerate() {
+                output[(self.position + index) % FRAME_SIZE] += bin.re * self.window[index];
+            }
+        }
+    }
+
+    // Produces the next frame of background noise to frame, with a sample for each channel, with
+    // the spectrum of the num_quietest quietest clips
+    pub fn produce(
+        &mut self,
+        background_sampler: &BackgroundSampler,
+        num_quietest: usize,
+        frame: &mut [f32],
+    ) {
+        let estimated_from = Some((background_sampler.generation(), num_quietest));
+        if self.estimated_from != estimated_from {
+            self.estimated_from = estimated_from;
+            self.estimate(background_sampler, num_quietest);
+        }
+        for (sample, output) in frame.iter_mut().zip(self.output.iter_mut()) {
+            *sample = output[self.position];
+            output[self.position] = 0.0;
+        }
+        self.position = (self.position + 1) % FRAME_SIZE;
+        self.hop_position += 1;
+        if self.hop_position == HOP_SIZE {
+            self.hop_position = 0;
+            self.synthesize_frame();
+        }
+    }
+}
@@ -1,3 +1,9 @@
+use crate::click_source::{ClickClass, ClickKind};
+use crate::comfort_noise;
+use crate::device_info::DeviceInfo;
+use crate::fade_curve::FadeCurve;
+use crate::key_names;
+use crate::transient_detector;
 use serde_derive::{Deserialize, Serialize};
 use std::fmt;
 use std::fs;
@@ -5,16 +11,299 @@ use std::io;
 use std::io::Write;
 use thiserror::Error;
 
+// Overrides for the mute_offset and mute_duration of Delays for key presses or key releases
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct EventDelays {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mute_offset: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mute_duration: Option<f64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub struct Delays {
     pub mute_offset: f64,
     pub mute_duration: f64,
     pub fade: f64,
+    // the shape of the fades and of the cross-fades to the background noise
+    #[serde(default)]
+    pub curve: FadeCurve,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub press: Option<EventDelays>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release: Option<EventDelays>,
+}
+
+// Selects input devices; all the given fields must match. vendor and product are the USB (or
+// other bus) ids of the device and glob is matched against both the name and the physical path,
+// with * matching any number of characters and ? matching any one character.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DeviceSelector {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phys: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glob: Option<String>,
+}
+
+// Which input devices are used for muting: if allow is empty, all devices are allowed; devices
+// matching any of the deny selectors are never used.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Devices {
+    #[serde(default)]
+    pub allow: Vec<DeviceSelector>,
+    #[serde(default)]
+    pub deny: Vec<DeviceSelector>,
+}
+
+// Whether clicks of a class trigger muting, and optionally the delays to use for them instead of
+// the global ones
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ClassConfig {
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delays: Option<Delays>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Classes {
+    pub keyboard: ClassConfig,
+    pub mouse: ClassConfig,
+    pub wheel: ClassConfig,
+    pub touchpad: ClassConfig,
+}
+
+impl Default for Classes {
+    fn default() -> Classes {
+        let enabled = |enabled| ClassConfig {
+            enabled,
+            delays: None,
+        };
+        Classes {
+            keyboard: enabled(true),
+            mouse: enabled(true),
+            wheel: enabled(false),
+            touchpad: enabled(true),
+        }
+    }
+}
+
+impl Classes {
+    pub fn get(&self, class: ClickClass) -> &ClassConfig {
+        match class {
+            ClickClass::Keyboard => &self.keyboard,
+            ClickClass::Mouse => &self.mouse,
+            ClickClass::Wheel => &self.wheel,
+            ClickClass::Touchpad => &self.touchpad,
+        }
+    }
+}
+
+// Delays to use instead of the global ones for the clicks from the matching devices
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceProfile {
+    pub device: DeviceSelector,
+    pub delays: Delays,
+}
+
+// A key given by its name in linux/input-event-codes.h, such as "KEY_LEFTCTRL", or by its code
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Key {
+    Code(u16),
+    Name(String),
+}
+
+// Keys that control the muting directly and never cause clicks. Each is a chord: all its keys
+// need to be held. With push_to_talk the output is muted unless it is held, and with push_to_mute
+// the output is muted while it is held.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Hotkeys {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub push_to_talk: Vec<Key>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub push_to_mute: Vec<Key>,
+}
+
+// Jack ports to connect to automatically, one pattern per channel: the output ports matching
+// inputs[0] are connected to in_a, out_a is connected to the input ports matching outputs[0], and
+// so on. The patterns match full port names, such as "system:capture_1", with * and ? wildcards.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Connect {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+}
+
+// What triggers the muting: the events of the input devices, transients detected in the audio,
+// or both, in which case the events arm the detection and the transients give the timing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerMode {
+    Evdev,
+    Audio,
+    Both,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Trigger {
+    pub mode: TriggerMode,
+    // how many dB above the background the high frequency energy of a transient rises
+    pub threshold: f64,
+    // with the evdev mode, line the mute windows up with the transients detected within them
+    pub align: bool,
+}
+
+impl Default for Trigger {
+    fn default() -> Trigger {
+        Trigger {
+            mode: TriggerMode::Evdev,
+            threshold: 15.0,
+            align: false,
+        }
+    }
+}
+
+// When aligning, transients are also looked for this many seconds before the mute window of the
+// event, for the events that arrive late
+pub const ALIGN_RANGE_SECONDS: f64 = 0.03;
+
+impl Trigger {
+    // Whether the transients in the audio are detected
+    pub fn uses_transients(&self) -> bool {
+        self.mode != TriggerMode::Evdev || self.align
+    }
+}
+
+// Where the background noise played during the mute windows comes from: looping the quietest
+// clips sampled from the input, or noise synthesized with their spectrum
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackgroundSource {
+    Loop,
+    ComfortNoise,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Background {
+    pub source: BackgroundSource,
+    // how many of the latest clips are kept
+    pub num_clips: usize,
+    // the length of the clips sampled, and how much the looped clips overlap, in milliseconds
+    pub clip_length_ms: f64,
+    pub overlap_ms: f64,
+    // the background noise is made of this many of the quietest clips
+    pub num_quietest: usize,
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background {
+            source: BackgroundSource::Loop,
+            num_clips: 20,
+            clip_length_ms: 20.0,
+            overlap_ms: 5.0,
+            num_quietest: 10,
+        }
+    }
+}
+
+impl Background {
+    // Describes what is wrong with the settings, if anything; the length of the clips in samples
+    // can be checked once the sample rate is known
+    pub fn error(&self, sample_rate: Option<usize>) -> Option<&'static str> {
+        if self.num_clips == 0 || self.num_quietest == 0 {
+            Some("background num_clips and num_quietest must be at least 1")
+        } else if !(self.clip_length_ms > 0.0
+            && (0.0..=self.clip_length_ms / 2.0).contains(&self.overlap_ms))
+        {
+            Some("background clip_length_ms must be positive and overlap_ms at most half of it")
+        } else if sample_rate.map_or(false, |x| {
+            self.clip_length_ms < Background::min_clip_length_ms(x)
+        }) {
+            Some("background clip_length_ms must be at least 513 samples")
+        } else {
+            None
+        }
+    }
+
+    // The shortest clips that can be analyzed, in milliseconds
+    pub fn min_clip_length_ms(sample_rate: usize) -> f64 {
+        (comfort_noise::MIN_CLIP_LENGTH as f64 * 1000.0 / sample_rate as f64).ceil()
+    }
+
+    // Shorter clips than can be analyzed are lengthened to that
+    pub fn clip_length(&self, sample_rate: usize) -> usize {
+        usize::max(
+            comfort_noise::MIN_CLIP_LENGTH,
+            (self.clip_length_ms / 1000.0 * sample_rate as f64) as usize,
+        )
+    }
+
+    pub fn overlap(&self, sample_rate: usize) -> usize {
+        (self.overlap_ms / 1000.0 * sample_rate as f64) as usize
+    }
+}
+
+// Ports are named with letters, in_a, in_b and so on
+pub const MAX_CHANNELS: usize = 26;
+
+fn default_channels() -> usize {
+    2
+}
+
+// How the clicks are suppressed: by muting (or attenuating) everything in the mute windows, or
+// by attenuating only the frequencies where the click dominates
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Suppression {
+    #[default]
+    Mute,
+    Spectral,
+}
+
+// Attenuations at or below this many dB mute completely
+pub const MIN_ATTENUATION: f64 = -60.0;
+
+fn default_attenuation() -> f64 {
+    MIN_ATTENUATION
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
+    // the number of input and output channels; changes take effect on restart
+    #[serde(default = "default_channels")]
+    pub channels: usize,
+    // how many dB the clicks are attenuated, down to MIN_ATTENUATION
+    #[serde(default = "default_attenuation")]
+    pub attenuation: f64,
+    #[serde(default)]
+    pub suppression: Suppression,
     pub delays: Delays,
+    #[serde(default)]
+    pub devices: Devices,
+    #[serde(default)]
+    pub classes: Classes,
+    #[serde(default)]
+    pub profiles: Vec<DeviceProfile>,
+    #[serde(default)]
+    pub hotkeys: Hotkeys,
+    #[serde(default)]
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub background: Background,
+    // used when starting; changes take effect on restart
+    #[serde(default)]
+    pub connect: Connect,
 }
 
 #[derive(Error, Debug)]
@@ -55,8 +344,23 @@ impl Config {
             mute_offset: -0.04,
             mute_duration: 0.08,
             fade: 0.01,
+            curve: FadeCurve::Linear,
+            press: None,
+            release: None,
         };
-        Config { delays }
+        Config {
+            channels: default_channels(),
+            attenuation: default_attenuation(),
+            suppression: Suppression::Mute,
+            delays,
+            devices: Devices::default(),
+            classes: Classes::default(),
+            profiles: vec![],
+            hotkeys: Hotkeys::default(),
+            trigger: Trigger::default(),
+            background: Background::default(),
+            connect: Connect::default(),
+        }
     }
 
     // If no file is found, returns default config instead of error
@@ -66,7 +370,7 @@ impl Config {
             Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Config::new()),
             Err(error) => return Err(Error::IOError(error)),
         };
-        let config = match toml::from_str(&contents) {
+        let config: Config = match toml::from_str(&contents) {
             Ok(contents) => contents,
             Err(error) if error.line_col().is_some() => {
                 return Err(Error::ParseError(ParseError {
@@ -76,14 +380,321 @@ impl Config {
             }
             Err(error) => return Err(Error::TomlDeError(error)),
         };
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return Err(Error::ParseError(ParseError {
+                filename: String::from(filename),
+                message: format!("channels must be between 1 and {}", MAX_CHANNELS),
+            }));
+        }
+        if config.connect.inputs.len() > config.channels
+            || config.connect.outputs.len() > config.channels
+        {
+            return Err(Error::ParseError(ParseError {
+                filename: String::from(filename),
+                message: String::from("connect has more patterns than there are channels"),
+            }));
+        }
+        if let Some(message) = config.all_delays().find_map(|x| x.error()) {
+            return Err(Error::ParseError(ParseError {
+                filename: String::from(filename),
+                message: String::from(message),
+            }));
+        }
+        if let Some(message) = config.background.error(None) {
+            return Err(Error::ParseError(ParseError {
+                filename: String::from(filename),
+                message: String::from(message),
+            }));
+        }
+        if let Some(name) = config.hotkeys.unknown_key_name() {
+            return Err(Error::ParseError(ParseError {
+                filename: String::from(filename),
+                message: format!("unknown key name {:?}", name),
+            }));
+        }
         println!("Loaded config from {}", filename);
         Ok(config)
     }
 
-    pub fn save(self, filename: &str) -> Result<(), Error> {
-        let contents = toml::to_string(&self)?;
+    pub fn save(&self, filename: &str) -> Result<(), Error> {
+        let contents = toml::to_string(self)?;
         let writer = atomicwrites::AtomicFile::new(filename, atomicwrites::AllowOverwrite);
         writer.write(|f| f.write_all(contents.as_bytes()))?;
         Ok(())
     }
+
+    // Delays to use for the clicks of the given class from the given device: a matching device
+    // profile is preferred over the delays of the class
+    pub fn click_delays(&self, class: ClickClass, device: Option<&DeviceInfo>) -> &Delays {
+        device
+            .and_then(|device| self.profiles.iter().find(|x| x.device.matches(device)))
+            .map(|x| &x.delays)
+            .or_else(|| self.classes.get(class).delays.as_ref())
+            .unwrap_or(&self.delays)
+    }
+
+    // The delays of the profiles, the classes and the default delays
+    fn all_delays(&self) -> impl Iterator<Item = &Delays> {
+        let classes = &self.classes;
+        self.profiles
+            .iter()
+            .map(|x| &x.delays)
+            .chain(
+                IntoIterator::into_iter([
+                    &classes.keyboard,
+                    &classes.mouse,
+                    &classes.wheel,
+                    &classes.touchpad,
+                ])
+                .filter_map(|x| x.delays.as_ref()),
+            )
+            .chain(std::iter::once(&self.delays))
+    }
+
+    // The largest amount of time we need to look ahead for any of the delays
+    pub fn max_delay(&self) -> f64 {
+        let all_delays = self.all_delays();
+        // transients in the audio are muted starting a fade before they were detected
+        let transient_delay = |x: &Delays| x.fade + transient_detector::LATENCY_SECONDS;
+        match self.trigger.mode {
+            TriggerMode::Evdev if !self.trigger.align => all_delays
+                .map(|x| f64::max(0.0, -x.min_mute_offset()))
+                .fold(0.0, f64::max),
+            TriggerMode::Audio => transient_delay(&self.delays),
+            // the aligned windows start a fade before the transients, which may be at the start
+            // of the mute windows of the events, or before them when aligning
+            TriggerMode::Evdev => all_delays
+                .map(|x| {
+                    f64::max(0.0, -x.min_mute_offset()) + ALIGN_RANGE_SECONDS + transient_delay(x)
+                })
+                .fold(0.0, f64::max),
+            TriggerMode::Both => all_delays
+                .map(|x| f64::max(0.0, -x.min_mute_offset()) + transient_delay(x))
+                .fold(0.0, f64::max),
+        }
+    }
+
+    // The gain to fade to when muting
+    pub fn attenuation_gain(&self) -> f32 {
+        if self.attenuation <= MIN_ATTENUATION {
+            0.0
+        } else {
+            10.0f64.powf(f64::min(0.0, self.attenuation) / 20.0) as f32
+        }
+    }
+}
+
+// Matches text against a pattern where * matches any number of characters and ? any one
+// character. Doesn't allocate, so it can be used in the Jack processing thread.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let next_char = |x: &str, index: usize| x[index..].chars().next();
+    // byte index after the last * seen in pattern and the text index it was matched at
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while let Some(text_char) = next_char(text, t) {
+        match next_char(pattern, p) {
+            // a * is a wildcard even when the text has a * too
+            Some('*') => {
+                p += 1;
+                star = Some((p, t));
+            }
+            Some(pattern_char) if pattern_char == '?' || pattern_char == text_char => {
+                p += pattern_char.len_utf8();
+                t += text_char.len_utf8();
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    // let the last * consume one more character
+                    let star_t = star_t + next_char(text, star_t).unwrap().len_utf8();
+                    p = star_p;
+                    t = star_t;
+                    star = Some((star_p, star_t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].chars().all(|x| x == '*')
+}
+
+impl DeviceSelector {
+    pub fn is_empty(&self) -> bool {
+        *self == DeviceSelector::default()
+    }
+
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        !self.is_empty()
+            && self.name.as_ref().map_or(true, |x| *x == device.name)
+            && self.phys.as_ref().map_or(true, |x| *x == device.phys)
+            && self.vendor.map_or(true, |x| x == device.vendor)
+            && self.product.map_or(true, |x| x == device.product)
+            && self.glob.as_ref().map_or(true, |x| {
+                glob_match(x, &device.name) || glob_match(x, &device.phys)
+            })
+    }
+}
+
+impl Delays {
+    fn event_delays(&self, kind: ClickKind) -> Option<&EventDelays> {
+        match kind {
+            ClickKind::Press => self.press.as_ref(),
+            ClickKind::Release => self.release.as_ref(),
+        }
+    }
+
+    pub fn mute_offset_for(&self, kind: ClickKind) -> f64 {
+        self.event_delays(kind)
+            .and_then(|x| x.mute_offset)
+            .unwrap_or(self.mute_offset)
+    }
+
+    pub fn mute_duration_for(&self, kind: ClickKind) -> f64 {
+        self.event_delays(kind)
+            .and_then(|x| x.mute_duration)
+            .unwrap_or(self.mute_duration)
+    }
+
+    // Describes what is wrong with the delays, including the overrides for presses and releases,
+    // if anything
+    pub fn error(&self) -> Option<&'static str> {
+        let kinds = [ClickKind::Press, ClickKind::Release];
+        if self.fade < 0.0 {
+            Some("fade cannot be negative")
+        } else if self.mute_offset > self.mute_duration
+            || kinds
+                .iter()
+                .any(|kind| self.mute_offset_for(*kind) > self.mute_duration_for(*kind))
+        {
+            Some("mute_offset cannot be after the end of the mute window at mute_duration")
+        } else {
+            None
+        }
+    }
+
+    // The most negative mute offset for any kind of event
+    fn min_mute_offset(&self) -> f64 {
+        [ClickKind::Press, ClickKind::Release]
+            .iter()
+            .map(|kind| self.mute_offset_for(*kind))
+            .fold(self.mute_offset, f64::min)
+    }
+}
+
+impl Key {
+    pub fn code(&self) -> Option<u16> {
+        match self {
+            Key::Code(code) => Some(*code),
+            Key::Name(name) => key_names::key_code(name),
+        }
+    }
+}
+
+impl Hotkeys {
+    // The first key name that is not known, if any
+    fn unknown_key_name(&self) -> Option<&str> {
+        self.push_to_talk
+            .iter()
+            .chain(self.push_to_mute.iter())
+            .find_map(|x| match x {
+                Key::Name(name) if x.code().is_none() => Some(name.as_str()),
+                _ => None,
+            })
+    }
+}
+
+impl Devices {
+    pub fn is_enabled(&self, device: &DeviceInfo) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|x| x.matches(device)))
+            && !self.deny.iter().any(|x| x.matches(device))
+    }
+
+    // Adjusts the selectors so that the device is enabled or disabled, preferring to remove an
+    // earlier selection for just that device. The device is selected by its physical path, or by
+    // its name and ids if it has no physical path, as then the path would select all such devices.
+    pub fn set_enabled(&mut self, device: &DeviceInfo, enabled: bool) {
+        let only_device = if device.phys.is_empty() {
+            DeviceSelector {
+                name: Some(device.name.clone()),
+                vendor: Some(device.vendor),
+                product: Some(device.product),
+                ..Default::default()
+            }
+        } else {
+            DeviceSelector {
+                phys: Some(device.phys.clone()),
+                ..Default::default()
+            }
+        };
+        if enabled {
+            self.deny.retain(|x| *x != only_device);
+            if !self.is_enabled(device) {
+                self.allow.push(only_device);
+            }
+        } else {
+            self.allow.retain(|x| *x != only_device);
+            if self.is_enabled(device) {
+                self.deny.push(only_device);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "Power Button"));
+        assert!(glob_match("**", "Power Button"));
+        assert!(glob_match("*Button", "Power Button"));
+        assert!(glob_match("Power*", "Power Button"));
+        assert!(glob_match("*wer*Bu*", "Power Button"));
+        assert!(glob_match("*a", "*ba"));
+        assert!(!glob_match("*Mouse*", "Power Button"));
+        assert!(!glob_match("Power", "Power Button"));
+        assert!(!glob_match("", "Power Button"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("?", "a"));
+        assert!(glob_match("usb-?/input?", "usb-1/input0"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("??", "a"));
+        assert!(glob_match("?*", "a"));
+    }
+
+    #[test]
+    fn glob_non_ascii() {
+        assert!(glob_match("Hiiri ?", "Hiiri ä"));
+        assert!(glob_match("*ö*", "Näppäimistö 2"));
+        assert!(glob_match("N?ppäimistö*", "Näppäimistö 2"));
+        assert!(!glob_match("N?ppäimistö", "Nappaimisto"));
+    }
+
+    fn device(name: &str, phys: &str) -> DeviceInfo {
+        DeviceInfo {
+            name: String::from(name),
+            phys: String::from(phys),
+            vendor: 0x046d,
+            product: 0xc52b,
+            touchpad: false,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn set_enabled_without_phys() {
+        let mouse = device("Mouse", "");
+        let keyboard = device("Keyboard", "");
+        let mut devices = Devices::default();
+        devices.set_enabled(&mouse, false);
+        assert!(!devices.is_enabled(&mouse));
+        assert!(devices.is_enabled(&keyboard));
+        devices.set_enabled(&mouse, true);
+        assert!(devices.is_enabled(&mouse));
+        assert_eq!(devices, Devices::default());
+    }
 }
@@ -0,0 +1,348 @@
+use crate::click_info::ClickInfo;
+use crate::click_mute_control;
+use crate::config::{Background, BackgroundSource, Config, Delays};
+use crate::recording::{self, Tap};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+use thiserror::Error;
+
+// How many events may be waiting for the subscribers before new ones are dropped
+pub const EVENT_QUEUE_SIZE: usize = 1024;
+
+// Subscribers that don't read their events in this time are dropped
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+// The control socket accepts one JSON request per line, and answers each with one JSON line:
+//
+//   {"command": "get"}
+//   {"command": "set", "mute_enabled": true, "invert_mute": false, "background_noise": true}
+//   {"command": "set_delays", "mute_offset": -0.045, "mute_duration": 0.05, "fade": 0.01}
+//   {"command": "set_background", "source": "loop", "num_clips": 20, "clip_length_ms": 20.0,
+//    "overlap_ms": 5.0, "num_quietest": 10}
+//   {"command": "start_recording", "taps": ["input", "output", "gate"]}
+//   {"command": "stop_recording"}
+//   {"command": "subscribe"}
+//
+// The fields of set, set_delays, set_background and start_recording are optional; without taps,
+// all of them are recorded. The other commands than subscribe answer with the state after the
+// request, and subscribe is followed by click and mute events until the connection is closed.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    Get,
+    Set {
+        mute_enabled: Option<bool>,
+        invert_mute: Option<bool>,
+        background_noise: Option<bool>,
+    },
+    SetDelays {
+        mute_offset: Option<f64>,
+        mute_duration: Option<f64>,
+        fade: Option<f64>,
+    },
+    SetBackground {
+        source: Option<BackgroundSource>,
+        num_clips: Option<usize>,
+        clip_length_ms: Option<f64>,
+        overlap_ms: Option<f64>,
+        num_quietest: Option<usize>,
+    },
+    StartRecording {
+        taps: Option<Vec<Tap>>,
+    },
+    StopRecording,
+    Subscribe,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    State {
+        mute_enabled: bool,
+        invert_mute: bool,
+        background_noise: bool,
+        num_clicks: usize,
+        delays: Delays,
+        background: Background,
+        recording: Option<String>,       // the directory being recorded to
+        recording_error: Option<String>, // what stopped the latest recording
+    },
+    Subscribed,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Control socket {0} is already in use")]
+    InUse(String),
+
+    #[error("Control socket {0} exists and is not a socket")]
+    NotSocket(String),
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+}
+
+type Subscribers = Arc<Mutex<Vec<UnixStream>>>;
+
+// ControlSocket serves the control socket in background threads; the socket file is removed
+// when this is dropped
+pub struct ControlSocket {
+    path: String,
+}
+
+impl Response {
+    fn state(click_info: &ClickInfo) -> Response {
+        Response::State {
+            mute_enabled: click_info.mute_enabled,
+            invert_mute: click_info.invert_mute,
+            background_noise: click_info.background_noise,
+            num_clicks: click_info.num_clicks,
+            delays: click_info.config.delays,
+            background: click_info.config.background,
+            recording: click_info.recording.clone(),
+            recording_error: click_info.recording_error.clone(),
+        }
+    }
+
+    fn error(message: &str) -> Response {
+        Response::Error {
+            message: String::from(message),
+        }
+    }
+}
+
+// Writes a value as a single line, in a single write, so lines written from different threads
+// don't get mixed up
+fn write_line<T: serde::Serialize>(stream: &mut UnixStream, value: &T) -> io::Result<()> {
+    let mut line = serde_json::to_string(value)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())
+}
+
+// Sends the changed configuration to ClickMute
+fn update_config(
+    click_info: &mut ClickInfo,
+    control: &click_mute_control::Sender,
+    config: Config,
+) -> Response {
+    if config != click_info.config {
+        click_info.config = config.clone();
+        if control
+            .send(click_mute_control::Message::UpdateConfig(Box::new(config)))
+            .is_err()
+        {
+            return Response::error("ClickMute is not running");
+        }
+    }
+    Response::state(click_info)
+}
+
+fn set_delays(
+    click_info: &mut ClickInfo,
+    control: &click_mute_control::Sender,
+    (mute_offset, mute_duration, fade): (Option<f64>, Option<f64>, Option<f64>),
+) -> Response {
+    if mute_duration.map_or(false, |x| x < 0.0) || fade.map_or(false, |x| x < 0.0) {
+        return Response::error("mute_duration and fade cannot be negative");
+    }
+    let mut config = click_info.config.clone();
+    config.delays.mute_offset = mute_offset.unwrap_or(config.delays.mute_offset);
+    config.delays.mute_duration = mute_duration.unwrap_or(config.delays.mute_duration);
+    config.delays.fade = fade.unwrap_or(config.delays.fade);
+    if let Some(message) = config.delays.error() {
+        return Response::error(message);
+    }
+    update_config(click_info, control, config)
+}
+
+// Takes the lock of click_info itself, as the files of the recording are created without it
+fn start_recording(
+    shared: &Arc<Mutex<ClickInfo>>,
+    control: &click_mute_control::Sender,
+    taps: &[Tap],
+) -> Response {
+    match recording::start(shared, taps) {
+        Ok(recorder) => {
+            let message = click_mute_control::Message::StartRecording(Box::new(recorder));
+            let failed = control.send(message).is_err();
+            let mut click_info = shared.lock().unwrap();
+            if failed {
+                click_info.recording = None;
+                return Response::error("ClickMute is not running");
+            }
+            Response::state(&click_info)
+        }
+        Err(error) => Response::error(&error.to_string()),
+    }
+}
+
+fn handle_request(
+    request: Request,
+    shared: &Arc<Mutex<ClickInfo>>,
+    control: &click_mute_control::Sender,
+) -> Response {
+    match request {
+        Request::Get => Response::state(&shared.lock().unwrap()),
+        Request::Set {
+            mute_enabled,
+            invert_mute,
+            background_noise,
+        } => {
+            let mut click_info = shared.lock().unwrap();
+            click_info.mute_enabled = mute_enabled.unwrap_or(click_info.mute_enabled);
+            click_info.invert_mute = invert_mute.unwrap_or(click_info.invert_mute);
+            click_info.background_noise = background_noise.unwrap_or(click_info.background_noise);
+            // like in the GUI, inverted muting doesn't go with background noise
+            if click_info.invert_mute {
+                click_info.background_noise = false;
+            }
+            Response::state(&click_info)
+        }
+        Request::SetDelays {
+            mute_offset,
+            mute_duration,
+            fade,
+        } => set_delays(
+            &mut shared.lock().unwrap(),
+            control,
+            (mute_offset, mute_duration, fade),
+        ),
+        Request::SetBackground {
+            source,
+            num_clips,
+            clip_length_ms,
+            overlap_ms,
+            num_quietest,
+        } => {
+            let mut click_info = shared.lock().unwrap();
+            let mut config = click_info.config.clone();
+            let background = &mut config.background;
+            background.source = source.unwrap_or(background.source);
+            background.num_clips = num_clips.unwrap_or(background.num_clips);
+            background.clip_length_ms = clip_length_ms.unwrap_or(background.clip_length_ms);
+            background.overlap_ms = overlap_ms.unwrap_or(background.overlap_ms);
+            background.num_quietest = num_quietest.unwrap_or(background.num_quietest);
+            match background.error(Some(click_info.sample_rate)) {
+                Some(message) => Response::error(message),
+                None => update_config(&mut click_info, control, config),
+            }
+        }
+        Request::StartRecording { taps } => {
+            start_recording(shared, control, taps.as_deref().unwrap_or(&Tap::ALL))
+        }
+        Request::StopRecording => {
+            let mut click_info = shared.lock().unwrap();
+            if click_info.recording.take().is_some() {
+                let _ = control.send(click_mute_control::Message::StopRecording);
+            }
+            Response::state(&click_info)
+        }
+        Request::Subscribe => Response::Subscribed,
+    }
+}
+
+fn client_thread(
+    stream: UnixStream,
+    click_info: Arc<Mutex<ClickInfo>>,
+    control: click_mute_control::Sender,
+    subscribers: Subscribers,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let subscribe = matches!(request, Request::Subscribe);
+                write_line(&mut writer, &handle_request(request, &click_info, &control))?;
+                if subscribe {
+                    let subscriber = writer.try_clone()?;
+                    subscriber.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    subscribers.lock().unwrap().push(subscriber);
+                }
+            }
+            Err(error) => write_line(&mut writer, &Response::error(&error.to_string()))?,
+        }
+    }
+    Ok(())
+}
+
+fn broadcast_thread(events: click_mute_control::EventReceiver, subscribers: Subscribers) {
+    for event in events.iter() {
+        // the writes may block, so they are done without the lock, and the subscribers added
+        // meanwhile go after the ones written to
+        let mut writing = mem::take(&mut *subscribers.lock().unwrap());
+        writing.retain_mut(|subscriber| write_line(subscriber, &event).is_ok());
+        let mut subscribers = subscribers.lock().unwrap();
+        writing.append(&mut subscribers);
+        *subscribers = writing;
+    }
+}
+
+impl ControlSocket {
+    pub fn new(
+        path: &str,
+        click_info: Arc<Mutex<ClickInfo>>,
+        control: click_mute_control::Sender,
+        events: click_mute_control::EventReceiver,
+    ) -> Result<ControlSocket, Error> {
+        // a socket left behind by a previous run can be replaced, but not one that is being used
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::InUse(String::from(path)));
+        }
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+            Ok(_) => return Err(Error::NotSocket(String::from(path))),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => return Err(Error::IOError(error)),
+        }
+        let listener = UnixListener::bind(path)?;
+
+        let subscribers: Subscribers = Arc::new(Mutex::new(vec![]));
+        {
+            let subscribers = subscribers.clone();
+            thread::spawn(move || broadcast_thread(events, subscribers));
+        }
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let click_info = click_info.clone();
+                        let control = control.clone();
+                        let subscribers = subscribers.clone();
+                        thread::spawn(move || {
+                            if let Err(error) =
+                                client_thread(stream, click_info, control, subscribers)
+                            {
+                                eprintln!("Control socket client failed: {}", error);
+                            }
+                        });
+                    }
+                    Err(error) => eprintln!("Failed to accept control socket client: {}", error),
+                }
+            }
+        });
+
+        Ok(ControlSocket {
+            path: String::from(path),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
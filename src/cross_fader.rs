@@ -1,6 +1,10 @@
+use crate::fade_curve::FadeCurve;
+
 pub struct CrossFader {
-    value: f32,
+    value: f32, // the position of the fade, from 0.0 (faded to sample_b) to 1.0
     step_per_sample: f32,
+    floor: f32, // how much of sample_a is left when faded out
+    curve: FadeCurve,
 }
 
 impl CrossFader {
@@ -8,6 +12,8 @@ impl CrossFader {
         CrossFader {
             value,
             step_per_sample: 0.0,
+            floor: 0.0,
+            curve: FadeCurve::Linear,
         }
     }
 
@@ -16,13 +22,22 @@ impl CrossFader {
         self.step_per_sample = 1.0 / (samples as f32);
     }
 
-    // fades to sample_b
+    // fades to sample_b, or to a mix of them if a floor has been set
     pub fn fade_out(&mut self, samples: usize) {
         self.step_per_sample = -1.0 / (samples as f32);
     }
 
+    pub fn set_floor(&mut self, floor: f32) {
+        self.floor = floor;
+    }
+
+    pub fn set_curve(&mut self, curve: FadeCurve) {
+        self.curve = curve;
+    }
+
     pub fn process(&mut self, sample_a: f32, sample_b: f32) -> f32 {
         self.value = f32::clamp(self.value + self.step_per_sample, 0.0, 1.0);
-        sample_a * self.value + sample_b * (1.0 - self.value)
+        let gain = self.curve.gain(self.value, self.floor);
+        sample_a * gain + sample_b * self.curve.complement(gain)
     }
 }
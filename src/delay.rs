@@ -5,8 +5,8 @@ pub struct Delay {
 }
 
 impl Delay {
+    // A delay of zero passes the samples through as they are
     pub fn new(delay: usize) -> Delay {
-        assert!(delay > 0);
         Delay {
             buffer: vec![0.0; delay],
             index: 0,
@@ -15,7 +15,9 @@ impl Delay {
     }
 
     pub fn process(&mut self, sample: f32) -> f32 {
-        if self.filled {
+        if self.buffer.is_empty() {
+            sample
+        } else if self.filled {
             let delay_sample = self.buffer[self.index];
             self.buffer[self.index] = sample;
             self.index += 1;
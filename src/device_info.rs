@@ -0,0 +1,35 @@
+// Information about an input device, as shown to the user and used for matching devices in the
+// configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub phys: String,
+    pub vendor: u16,
+    pub product: u16,
+    // the buttons of a touchpad are touchpad clicks rather than mouse clicks
+    pub touchpad: bool,
+    // is the device used for muting
+    pub enabled: bool,
+}
+
+impl DeviceInfo {
+    pub fn new(device: &evdev::Device) -> DeviceInfo {
+        let input_id = device.input_id();
+        DeviceInfo {
+            name: device.name().to_string_lossy().into_owned(),
+            phys: device
+                .physical_path()
+                .as_ref()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            vendor: input_id.vendor,
+            product: input_id.product,
+            // touchpads report fingers on the pad and move the pointer, like libinput finds them
+            touchpad: device.properties().contains(evdev::POINTER)
+                && device
+                    .keys_supported()
+                    .contains(evdev::Key::BTN_TOOL_FINGER as usize),
+            enabled: true,
+        }
+    }
+}
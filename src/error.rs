@@ -8,6 +8,15 @@ pub enum Error {
     #[error(transparent)]
     ClickMuteError(#[from] crate::click_mute::Error),
 
+    #[error(transparent)]
+    ControlSocketError(#[from] crate::control_socket::Error),
+
+    #[error(transparent)]
+    EventLogError(#[from] crate::event_log::Error),
+
+    #[error(transparent)]
+    OfflineError(#[from] crate::offline::Error),
+
     #[error("unsupported path")]
     UnsupportedPath(String), // message
 }
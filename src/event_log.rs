@@ -0,0 +1,498 @@
+use crate::click_source::{Click, ClickClass, ClickSource, EV_KEY};
+use crate::config::Config;
+use crate::device_info::DeviceInfo;
+use crate::hotkeys::Hotkeys;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+// Events older than this (in seconds) when they are read are dropped, see
+// https://github.com/eras/ClickMuteJack/issues/6
+pub const MAX_EVENT_AGE: f64 = 0.100;
+
+// An input event as found in an event log. The format is line based, one event per line:
+//
+//   <time> <type> <code> <value> [<device> [<read time>]]
+//
+// where time is the kernel timestamp of the event in seconds, type, code and value are the evdev
+// event fields, device is the physical path of the device the event came from (or - if not
+// known) and read time is the time in seconds when the event was read from the device. Empty
+// lines and lines starting with # are ignored.
+//
+// The devices can be described by lines of the form
+//
+//   device <device> <vendor> <product> <touchpad> <name>
+//
+// where device is the physical path as in the events, vendor and product are in hexadecimal,
+// touchpad is 1 or 0 and name is the rest of the line. Without them, only the physical paths of
+// the devices are known when replaying.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub time: f64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+    pub device: Option<String>,
+    pub read_time: Option<f64>,
+}
+
+#[derive(Error, Debug)]
+pub struct ParseError {
+    pub filename: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to parse {} line {}: {}",
+            self.filename, self.line, self.message
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    ParseError(ParseError),
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+}
+
+fn parse_time(time: &str) -> Result<f64, String> {
+    time.parse().map_err(|_| format!("invalid time {:?}", time))
+}
+
+// seconds and microseconds, as in struct timeval
+pub fn timeval_seconds(sec: i64, usec: i64) -> f64 {
+    sec as f64 + usec as f64 / 1000000.0
+}
+
+impl Event {
+    pub fn parse(line: &str) -> Result<Event, String> {
+        let mut fields = line.split_whitespace();
+        let mut next = |name: &str| fields.next().ok_or(format!("missing field {}", name));
+        let time = parse_time(next("time")?)?;
+        let kind = next("type")?;
+        let code = next("code")?;
+        let value = next("value")?;
+        let device = fields.next().filter(|x| *x != "-").map(String::from);
+        let read_time = match fields.next() {
+            None => None,
+            Some(read_time) => Some(parse_time(read_time)?),
+        };
+        Ok(Event {
+            time,
+            kind: kind
+                .parse()
+                .map_err(|_| format!("invalid type {:?}", kind))?,
+            code: code
+                .parse()
+                .map_err(|_| format!("invalid code {:?}", code))?,
+            value: value
+                .parse()
+                .map_err(|_| format!("invalid value {:?}", value))?,
+            device,
+            read_time,
+        })
+    }
+}
+
+// The devices and the events of an event log
+pub struct Log {
+    pub devices: Vec<DeviceInfo>,
+    pub events: Vec<Event>,
+}
+
+// A line of an event log
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Device(DeviceInfo),
+    Event(Event),
+}
+
+fn parse_device(line: &str) -> Result<DeviceInfo, String> {
+    let mut fields = line
+        .splitn(6, char::is_whitespace)
+        .filter(|x| !x.is_empty());
+    let mut next = |name: &str| fields.next().ok_or(format!("missing field {}", name));
+    next("device")?;
+    let phys = next("phys")?;
+    let vendor = next("vendor")?;
+    let product = next("product")?;
+    let touchpad = next("touchpad")?;
+    let name = next("name").unwrap_or_default();
+    Ok(DeviceInfo {
+        name: name.trim().to_string(),
+        phys: String::from(phys),
+        vendor: u16::from_str_radix(vendor, 16)
+            .map_err(|_| format!("invalid vendor {:?}", vendor))?,
+        product: u16::from_str_radix(product, 16)
+            .map_err(|_| format!("invalid product {:?}", product))?,
+        touchpad: match touchpad {
+            "0" => false,
+            "1" => true,
+            _ => return Err(format!("invalid touchpad {:?}", touchpad)),
+        },
+        enabled: true,
+    })
+}
+
+impl Line {
+    fn parse(line: &str) -> Result<Line, String> {
+        if line.starts_with("device ") {
+            Ok(Line::Device(parse_device(line)?))
+        } else {
+            Ok(Line::Event(Event::parse(line)?))
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Device(device) => write!(
+                f,
+                "device {} {:04x} {:04x} {} {}",
+                device.phys,
+                device.vendor,
+                device.product,
+                if device.touchpad { 1 } else { 0 },
+                device.name
+            ),
+            Line::Event(event) => event.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.6} {} {} {} {}",
+            self.time,
+            self.kind,
+            self.code,
+            self.value,
+            self.device.as_deref().unwrap_or("-")
+        )?;
+        if let Some(read_time) = self.read_time {
+            write!(f, " {:.6}", read_time)?;
+        }
+        Ok(())
+    }
+}
+
+// Loads an event log; the events are returned in the order of their time stamps
+pub fn load(filename: &str) -> Result<Log, Error> {
+    let contents = fs::read_to_string(filename)?;
+    let mut devices = vec![];
+    let mut events = vec![];
+    for (line_index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Line::parse(line) {
+            Ok(Line::Device(device)) => devices.push(device),
+            Ok(Line::Event(event)) => events.push(event),
+            Err(message) => {
+                return Err(Error::ParseError(ParseError {
+                    filename: String::from(filename),
+                    line: line_index + 1,
+                    message,
+                }))
+            }
+        }
+    }
+    events.sort_by(|a, b| {
+        a.time
+            .partial_cmp(&b.time)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(Log { devices, events })
+}
+
+// Recorder writes events to an event log in a separate thread, so recording doesn't hold up the
+// evdev reader thread that records the events as they are read
+pub struct Recorder {
+    send: Option<mpsc::Sender<Line>>,
+    writer_join: Option<thread::JoinHandle<()>>,
+    described: HashSet<String>, // the physical paths of the devices described so far
+}
+
+impl Recorder {
+    pub fn new(filename: &str) -> Result<Recorder, Error> {
+        let mut file = io::BufWriter::new(fs::File::create(filename)?);
+        writeln!(file, "# time type code value device read_time")?;
+        let (send, recv) = mpsc::channel::<Line>();
+        let filename = String::from(filename);
+        let writer_join = thread::spawn(move || {
+            for line in recv.iter() {
+                let result = writeln!(file, "{}", line).and_then(|()| file.flush());
+                if let Err(error) = result {
+                    eprintln!("Failed to write event log {}: {}", filename, error);
+                    break;
+                }
+            }
+        });
+        Ok(Recorder {
+            send: Some(send),
+            writer_join: Some(writer_join),
+            described: HashSet::new(),
+        })
+    }
+
+    fn send(&self, line: Line) {
+        if let Some(ref send) = self.send {
+            // if the writer has failed, it has already reported the error
+            let _ = send.send(line);
+        }
+    }
+
+    // Records the event of the device, describing the device before its first event
+    pub fn record(&mut self, event: Event, device: &DeviceInfo) {
+        if !device.phys.is_empty() && self.described.insert(device.phys.clone()) {
+            self.send(Line::Device(device.clone()));
+        }
+        self.send(Line::Event(event));
+    }
+
+    pub fn stop(&mut self) {
+        self.send.take();
+        if let Some(join) = self.writer_join.take() {
+            join.join().unwrap();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+// Replay feeds the events of an event log to ClickMute as if they were arriving live, with the
+// current time given by the caller
+pub struct Replay {
+    events: Vec<Event>,
+    next_index: usize,
+    // the devices of the events by their physical paths; only the path is known about the
+    // devices not described in the log
+    devices: HashMap<String, Arc<DeviceInfo>>,
+    hotkeys: Hotkeys,
+}
+
+impl Replay {
+    pub fn new(log: Log) -> Replay {
+        let Log {
+            devices,
+            mut events,
+        } = log;
+        // events are delivered at the time they were read, if that is known
+        events.sort_by(|a, b| {
+            a.read_time
+                .unwrap_or(a.time)
+                .partial_cmp(&b.read_time.unwrap_or(b.time))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mut devices: HashMap<String, Arc<DeviceInfo>> = devices
+            .into_iter()
+            .map(|device| (device.phys.clone(), Arc::new(device)))
+            .collect();
+        for phys in events.iter().filter_map(|x| x.device.as_ref()) {
+            if !devices.contains_key(phys) {
+                let device = DeviceInfo {
+                    name: String::new(),
+                    phys: phys.clone(),
+                    vendor: 0,
+                    product: 0,
+                    touchpad: false,
+                    enabled: true,
+                };
+                devices.insert(phys.clone(), Arc::new(device));
+            }
+        }
+        Replay {
+            events,
+            next_index: 0,
+            devices,
+            hotkeys: Hotkeys::new(&Default::default()),
+        }
+    }
+
+    pub fn load(filename: &str) -> Result<Replay, Error> {
+        Ok(Replay::new(load(filename)?))
+    }
+
+    // The time of the first event in the log
+    pub fn start_time(&self) -> Option<f64> {
+        self.events.first().map(|x| x.read_time.unwrap_or(x.time))
+    }
+
+    // The devices not enabled by the configuration are left out, like with the live devices
+    pub fn update_config(&mut self, config: &Config) {
+        self.hotkeys = Hotkeys::new(&config.hotkeys);
+        for device in self.devices.values_mut() {
+            let enabled = config.devices.is_enabled(device);
+            if device.enabled != enabled {
+                Arc::make_mut(device).enabled = enabled;
+            }
+        }
+    }
+
+    pub fn is_hotkey_muted(&self) -> bool {
+        self.hotkeys.is_muted()
+    }
+
+    /** Like ClickSource::when_clicked, but the current time is given; appends the clicks
+     * delivered up to now */
+    pub fn when_clicked(&mut self, now: f64, clicks: &mut Vec<Click>) {
+        while self.next_index < self.events.len()
+            && self.events[self.next_index]
+                .read_time
+                .unwrap_or(self.events[self.next_index].time)
+                <= now
+        {
+            let event = &self.events[self.next_index];
+            let device = event
+                .device
+                .as_ref()
+                .and_then(|x| self.devices.get(x))
+                .cloned();
+            // the events of the devices that are not known are let through
+            if device.as_ref().map_or(false, |x| !x.enabled) {
+                self.next_index += 1;
+                continue;
+            }
+            let touchpad = device.as_ref().map_or(false, |x| x.touchpad);
+            // using the hotkeys doesn't cause clicks
+            let is_hotkey = event.kind == EV_KEY && self.hotkeys.key_event(event.code, event.value);
+            let classified = if is_hotkey {
+                None
+            } else {
+                ClickClass::classify(event.kind, event.code, event.value, touchpad)
+            };
+            if let Some((class, kind)) = classified {
+                let delta = f64::min(-0.0, event.time - now);
+                if delta < -MAX_EVENT_AGE {
+                    println!(
+                        "Dropped too old event {:?} at {} -> delta {} (issue #6)",
+                        kind, event.time, delta
+                    );
+                } else {
+                    clicks.push(Click {
+                        class,
+                        kind,
+                        delta,
+                        device,
+                    });
+                }
+            }
+            self.next_index += 1;
+        }
+    }
+}
+
+// LiveReplay replays an event log in real time, starting from the time it is created
+pub struct LiveReplay {
+    replay: Replay,
+    // add to the current time to get the time in the event log
+    time_offset: f64,
+}
+
+fn realtime_now() -> f64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    timeval_seconds(now.as_secs() as i64, now.subsec_micros() as i64)
+}
+
+impl LiveReplay {
+    pub fn new(replay: Replay) -> LiveReplay {
+        let time_offset = match replay.start_time() {
+            Some(start_time) => start_time - realtime_now(),
+            None => 0.0,
+        };
+        LiveReplay {
+            replay,
+            time_offset,
+        }
+    }
+}
+
+impl ClickSource for LiveReplay {
+    fn when_clicked(&mut self, clicks: &mut Vec<Click>) {
+        self.replay
+            .when_clicked(realtime_now() + self.time_offset, clicks)
+    }
+
+    fn is_hotkey_muted(&self) -> bool {
+        self.replay.is_hotkey_muted()
+    }
+
+    fn update_config(&mut self, config: &Config) {
+        self.replay.update_config(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_round_trip() {
+        let event = Event {
+            time: 1234.5,
+            kind: 1,
+            code: 30,
+            value: 1,
+            device: Some(String::from("usb-0000:00:14.0-1/input0")),
+            read_time: Some(1234.501),
+        };
+        assert_eq!(Event::parse(&event.to_string()), Ok(event));
+    }
+
+    #[test]
+    fn event_optional_fields() {
+        let event = Event::parse("0.5 1 272 0").unwrap();
+        assert_eq!(event.device, None);
+        assert_eq!(event.read_time, None);
+        let event = Event::parse("0.5 1 272 0 - 0.6").unwrap();
+        assert_eq!(event.device, None);
+        assert_eq!(event.read_time, Some(0.6));
+        assert_eq!(Event::parse(&event.to_string()), Ok(event));
+    }
+
+    #[test]
+    fn event_invalid() {
+        assert!(Event::parse("").is_err());
+        assert!(Event::parse("0.5 1 272").is_err());
+        assert!(Event::parse("x 1 272 0").is_err());
+        assert!(Event::parse("0.5 1 272 0 kbd x").is_err());
+    }
+
+    #[test]
+    fn device_round_trip() {
+        let device = Line::Device(DeviceInfo {
+            name: String::from("Logitech USB Receiver Mouse"),
+            phys: String::from("usb-0000:00:14.0-2/input1"),
+            vendor: 0x046d,
+            product: 0xc52b,
+            touchpad: true,
+            enabled: true,
+        });
+        assert_eq!(Line::parse(&device.to_string()), Ok(device));
+        assert!(Line::parse("device kbd 046d").is_err());
+        assert!(Line::parse("device kbd 046d c52b 2 Keyboard").is_err());
+    }
+}
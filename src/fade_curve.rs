@@ -0,0 +1,38 @@
+use serde_derive::{Deserialize, Serialize};
+use std::f32::consts::{FRAC_PI_2, PI};
+
+// The gain the exponential curve starts from when fading in from silence, -60 dB
+const EXPONENTIAL_MIN_GAIN: f32 = 0.001;
+
+// The shape of the fades, as the gain along a fade from 0.0 to 1.0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    EqualPower,   // sin/cos, keeps the sum of the powers constant when cross-fading
+    RaisedCosine, // starts and ends smoothly
+    Exponential,  // linear in dB
+}
+
+impl FadeCurve {
+    // The gain at the position (0.0..=1.0) of a fade between floor and 1.0
+    pub fn gain(&self, position: f32, floor: f32) -> f32 {
+        let shaped = |shape: f32| floor + (1.0 - floor) * shape;
+        match self {
+            FadeCurve::Linear => shaped(position),
+            FadeCurve::EqualPower => shaped(f32::sin(position * FRAC_PI_2)),
+            FadeCurve::RaisedCosine => shaped(0.5 - 0.5 * f32::cos(position * PI)),
+            FadeCurve::Exponential if position <= 0.0 => floor,
+            FadeCurve::Exponential => f32::max(floor, EXPONENTIAL_MIN_GAIN).powf(1.0 - position),
+        }
+    }
+
+    // The gain of the other signal of a cross-fade, when this one has the given gain
+    pub fn complement(&self, gain: f32) -> f32 {
+        match self {
+            FadeCurve::EqualPower => f32::sqrt(f32::max(0.0, 1.0 - gain * gain)),
+            _ => 1.0 - gain,
+        }
+    }
+}
@@ -1,6 +1,10 @@
+use crate::fade_curve::FadeCurve;
+
 pub struct Fader {
-    value: f32,
+    value: f32, // the position of the fade, from 0.0 (faded out) to 1.0
     step_per_sample: f32,
+    floor: f32, // the gain when faded out
+    curve: FadeCurve,
 }
 
 impl Fader {
@@ -8,6 +12,8 @@ impl Fader {
         Fader {
             value,
             step_per_sample: 0.0,
+            floor: 0.0,
+            curve: FadeCurve::Linear,
         }
     }
 
@@ -15,12 +21,21 @@ impl Fader {
         self.step_per_sample = 1.0 / (samples as f32);
     }
 
+    // fades to the floor, which is silence unless set_floor has been used
     pub fn fade_out(&mut self, samples: usize) {
         self.step_per_sample = -1.0 / (samples as f32);
     }
 
+    pub fn set_floor(&mut self, floor: f32) {
+        self.floor = floor;
+    }
+
+    pub fn set_curve(&mut self, curve: FadeCurve) {
+        self.curve = curve;
+    }
+
     pub fn process(&mut self, sample: f32) -> f32 {
         self.value = f32::clamp(self.value + self.step_per_sample, 0.0, 1.0);
-        sample * self.value
+        sample * self.curve.gain(self.value, self.floor)
     }
 }
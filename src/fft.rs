@@ -0,0 +1,165 @@
+use std::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn new(re: f32, im: f32) -> Complex {
+        Complex { re, im }
+    }
+
+    pub fn norm(&self) -> f32 {
+        f32::sqrt(self.re * self.re + self.im * self.im)
+    }
+
+    pub fn scale(&self, factor: f32) -> Complex {
+        Complex::new(self.re * factor, self.im * factor)
+    }
+
+    fn mul(&self, other: &Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+// Fft is an in-place radix-2 fast Fourier transform of a fixed power-of-two size. The tables are
+// computed beforehand, so transforms don't allocate and can be done in the Jack processing thread.
+pub struct Fft {
+    twiddles: Vec<Complex>, // exp(-2 pi i k / size) for the first half of k
+    bit_reverse: Vec<usize>,
+}
+
+impl Fft {
+    pub fn new(size: usize) -> Fft {
+        assert!(size.is_power_of_two());
+        let bits = size.trailing_zeros();
+        Fft {
+            twiddles: (0..size / 2)
+                .map(|k| {
+                    let angle = -2.0 * PI * k as f32 / size as f32;
+                    Complex::new(f32::cos(angle), f32::sin(angle))
+                })
+                .collect(),
+            bit_reverse: (0..size)
+                .map(|x| {
+                    if bits == 0 {
+                        0
+                    } else {
+                        x.reverse_bits() >> (usize::BITS - bits)
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.bit_reverse.len()
+    }
+
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        let size = self.size();
+        assert_eq!(data.len(), size);
+        for (index, &reversed) in self.bit_reverse.iter().enumerate() {
+            if index < reversed {
+                data.swap(index, reversed);
+            }
+        }
+        let mut length = 2;
+        while length <= size {
+            let twiddle_step = size / length;
+            for start in (0..size).step_by(length) {
+                for k in 0..length / 2 {
+                    let twiddle = self.twiddles[k * twiddle_step];
+                    let twiddle = if inverse {
+                        Complex::new(twiddle.re, -twiddle.im)
+                    } else {
+                        twiddle
+                    };
+                    let a = data[start + k];
+                    let b = data[start + k + length / 2].mul(&twiddle);
+                    data[start + k] = Complex::new(a.re + b.re, a.im + b.im);
+                    data[start + k + length / 2] = Complex::new(a.re - b.re, a.im - b.im);
+                }
+            }
+            length *= 2;
+        }
+    }
+
+    pub fn forward(&self, data: &mut [Complex]) {
+        self.transform(data, false);
+    }
+
+    // The inverse transform, scaled so that it undoes forward
+    pub fn inverse(&self, data: &mut [Complex]) {
+        self.transform(data, true);
+        let scale = 1.0 / self.size() as f32;
+        for x in data.iter_mut() {
+            *x = x.scale(scale);
+        }
+    }
+}
+
+// The square root of a periodic Hann window; used for both analysis and synthesis, the products
+// of the windows overlapping by a quarter of their size add up to 2
+pub fn sqrt_hann(size: usize) -> Vec<f32> {
+    (0..size)
+        .map(|x| f32::sin(PI * x as f32 / size as f32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn assert_close(a: &[Complex], b: &[Complex]) {
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!((a.re - b.re).abs() < 1e-4 && (a.im - b.im).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_forward() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for size in [1, 2, 8, 512] {
+            let fft = Fft::new(size);
+            let data: Vec<Complex> = (0..size)
+                .map(|_| Complex::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+                .collect();
+            let mut transformed = data.clone();
+            fft.forward(&mut transformed);
+            fft.inverse(&mut transformed);
+            assert_close(&transformed, &data);
+        }
+    }
+
+    #[test]
+    fn forward_of_cosine() {
+        let size = 64;
+        let fft = Fft::new(size);
+        let mut data: Vec<Complex> = (0..size)
+            .map(|x| Complex::new(f32::cos(2.0 * PI * 5.0 * x as f32 / size as f32), 0.0))
+            .collect();
+        fft.forward(&mut data);
+        let mut expected = vec![Complex::default(); size];
+        expected[5] = Complex::new(size as f32 / 2.0, 0.0);
+        expected[size - 5] = Complex::new(size as f32 / 2.0, 0.0);
+        assert_close(&data, &expected);
+    }
+
+    #[test]
+    fn sqrt_hann_overlap_adds_up() {
+        let size = 512;
+        let window = sqrt_hann(size);
+        for index in 0..size / 4 {
+            let sum: f32 = (0..4).map(|x| window[index + x * size / 4].powi(2)).sum();
+            assert!((sum - 2.0).abs() < 1e-4);
+        }
+    }
+}
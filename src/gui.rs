@@ -1,7 +1,12 @@
+use crate::calibration::{Calibration, Proposal};
 use crate::click_info::ClickInfo;
 use crate::click_mute_control;
-use crate::config::Config;
+use crate::config::{
+    Background, BackgroundSource, Config, Suppression, TriggerMode, MIN_ATTENUATION,
+};
+use crate::fade_curve::FadeCurve;
 use crate::level_event::LevelEvent;
+use crate::recording::{self, Tap};
 use egui::plot::{Curve, Plot, Value};
 use std::ops::RangeInclusive;
 use std::sync::{Arc, Mutex};
@@ -12,9 +17,179 @@ use {egui_miniquad as egui_mq, miniquad as mq};
 enum PlotMode {
     LiveSignal,
     Capture,
+    Devices,
+    Calibration,
+    Background,
+    Recording,
     NoView,
 }
 
+// The levels of the background clips are plotted in dB, down to this
+const MIN_PLOT_DB: f64 = -100.0;
+
+fn rms_to_db(rms: f32) -> f64 {
+    f64::max(MIN_PLOT_DB, 20.0 * f64::log10(rms as f64))
+}
+
+// A horizontal line at the level of rms across the plot of num_clips clips
+fn level_line(rms: f32, num_clips: f64) -> Curve {
+    let db = rms_to_db(rms);
+    Curve::from_values(vec![Value::new(0.0, db), Value::new(num_clips - 1.0, db)])
+}
+
+// Shows the settings of the background noise, and plots the levels of the clips sampled
+fn background_panel(
+    ui: &mut egui::Ui,
+    background: &mut Background,
+    click_info: &ClickInfo,
+    control: &click_mute_control::Sender,
+) {
+    ui.horizontal(|ui| {
+        ui.label("Source");
+        let source = &mut background.source;
+        ui.selectable_value(source, BackgroundSource::Loop, "Loop");
+        ui.selectable_value(source, BackgroundSource::ComfortNoise, "Comfort noise");
+        ui.separator();
+        if ui
+            .button("Clear clips")
+            .on_hover_text("Forget the clips sampled so far, such as after moving to another room")
+            .clicked()
+        {
+            control
+                .send(click_mute_control::Message::ClearBackground)
+                .unwrap();
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.add(egui::Slider::new(&mut background.num_clips, 1..=100).text("clips"));
+        ui.add(
+            egui::Slider::new(&mut background.num_quietest, 1..=background.num_clips)
+                .text("quietest used"),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::Slider::new(
+                &mut background.clip_length_ms,
+                Background::min_clip_length_ms(click_info.sample_rate)..=200.0,
+            )
+            .text("ms clip length")
+            .fixed_decimals(0),
+        );
+        ui.add(
+            egui::Slider::new(
+                &mut background.overlap_ms,
+                0.0..=background.clip_length_ms / 2.0,
+            )
+            .text("ms overlap")
+            .fixed_decimals(1),
+        );
+    });
+
+    let num_clips = background.num_clips as f64;
+    let clips = &click_info.background_clips;
+    let mut plot = Plot::new("Background clips")
+        .allow_zoom(false)
+        .allow_drag(false)
+        .curve(
+            Curve::from_values_iter(
+                clips
+                    .iter()
+                    .enumerate()
+                    .map(|(index, rms)| Value::new(index as f64, rms_to_db(*rms))),
+            )
+            .color(egui::Rgba::from_rgb(0.2, 0.2, 0.2))
+            .name("Clip RMS (dB)"),
+        )
+        .width(ui.available_size().x)
+        .height(ui.available_size().y)
+        .include_x(0.0)
+        .include_x(num_clips - 1.0)
+        .include_y(MIN_PLOT_DB)
+        .include_y(0.0);
+    // the quietest clips used for the background noise are the ones at or below the grey line
+    let mut sorted = clips.clone();
+    sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    if let Some(rms) = sorted.iter().take(background.num_quietest).last() {
+        plot = plot.curve(
+            level_line(*rms, num_clips)
+                .color(egui::Rgba::from_rgb(0.6, 0.6, 0.6))
+                .name("Quietest used"),
+        );
+    }
+    if let Some(floor) = click_info.noise_floor {
+        plot = plot.curve(
+            level_line(floor, num_clips)
+                .color(egui::Rgba::from_rgb(0.2, 0.2, 0.8))
+                .name("Noise floor"),
+        );
+    }
+    ui.add(plot);
+}
+
+// Shows the taps to record and starts and stops recording them
+// Returns whether a recording is to be started, which is done outside the lock of click_info
+fn recording_panel(
+    ui: &mut egui::Ui,
+    taps: &mut Vec<Tap>,
+    click_info: &mut ClickInfo,
+    control: &click_mute_control::Sender,
+) -> bool {
+    let mut start = false;
+    ui.horizontal(|ui| {
+        // the taps can't be changed in the middle of a recording
+        ui.set_enabled(click_info.recording.is_none());
+        for tap in Tap::ALL.iter() {
+            let mut enabled = taps.contains(tap);
+            if ui.checkbox(&mut enabled, tap.name()).changed() {
+                *taps = Tap::ALL
+                    .iter()
+                    .copied()
+                    .filter(|x| if x == tap { enabled } else { taps.contains(x) })
+                    .collect();
+            }
+        }
+    });
+    ui.horizontal(|ui| {
+        if click_info.recording.is_some() {
+            if ui.button("Stop").clicked() {
+                click_info.recording = None;
+                control
+                    .send(click_mute_control::Message::StopRecording)
+                    .unwrap();
+            }
+        } else if ui.button("Start").clicked() {
+            start = true;
+        }
+        match click_info.recording {
+            Some(ref dir) => ui.label(format!("Recording to {}", dir)),
+            None => ui.label(format!("Recordings go to {}", click_info.recording_dir)),
+        };
+    });
+    if let Some(ref error) = click_info.recording_error {
+        ui.colored_label(egui::Color32::RED, error);
+    }
+    start
+}
+
+fn start_recording(
+    taps: &[Tap],
+    shared: &Arc<Mutex<ClickInfo>>,
+    control: &click_mute_control::Sender,
+) {
+    match recording::start(shared, taps) {
+        Ok(recorder) => control
+            .send(click_mute_control::Message::StartRecording(Box::new(
+                recorder,
+            )))
+            .unwrap(),
+        Err(error) => shared.lock().unwrap().recording_error = Some(error.to_string()),
+    }
+}
+
+// How long the user is asked to type for calibration
+const CALIBRATION_SECONDS: f64 = 20.0;
+
 struct Stage {
     egui_mq: egui_mq::EguiMq,
     quit: LevelEvent,
@@ -32,6 +207,12 @@ struct Stage {
     autoscale_y: bool,
 
     config_file: String,
+
+    // the result of the latest calibration; None inside if no clicks could be found
+    calibration_result: Option<Option<Proposal>>,
+
+    // the taps to record when a recording is started
+    recording_taps: Vec<Tap>,
 }
 
 impl Stage {
@@ -53,6 +234,8 @@ impl Stage {
             control,
             origo_at_click: false,
             autoscale_y: true,
+            calibration_result: None,
+            recording_taps: Tap::ALL.to_vec(),
         }
     }
 
@@ -86,18 +269,41 @@ impl Stage {
     }
 
     fn ui(&mut self) {
+        // the configuration may have been changed elsewhere, such as through the control socket
+        {
+            let click_info = self.click_info.lock().unwrap();
+            if click_info.config != self.config {
+                self.config = click_info.config.clone();
+            }
+        }
+
         let plot_mode = &mut self.plot_mode;
-        let old_config = self.config;
+        let old_config = self.config.clone();
         let config = &mut self.config;
         let control = &mut self.control;
         let origo_at_click = &mut self.origo_at_click;
         let autoscale_y = &mut self.autoscale_y;
         let config_file = &self.config_file;
+        let calibration_result = &mut self.calibration_result;
+        let recording_taps = &mut self.recording_taps;
 
         let egui_ctx = self.egui_mq.egui_ctx();
 
         let click_info = self.click_info.clone();
 
+        // analyze the finished calibration outside the lock, as it takes a while
+        let finished_calibration = {
+            let mut click_info = click_info.lock().unwrap();
+            match click_info.calibration {
+                Some(ref calibration) if calibration.is_finished() => click_info.calibration.take(),
+                _ => None,
+            }
+        };
+        if let Some(calibration) = finished_calibration {
+            *calibration_result = Some(calibration.propose(&config.delays));
+        }
+
+        let mut start_recording_clicked = false;
         egui::CentralPanel::default().show(egui_ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.columns(5, |columns| {
@@ -168,18 +374,58 @@ impl Stage {
                     0.0..=0.2,
                 );
             });
-            if *config != old_config {
-                control
-                    .send(click_mute_control::Message::UpdateConfig(*config))
-                    .unwrap();
-            }
-
+            ui.horizontal(|ui| {
+                ui.label("Suppression");
+                let suppression = &mut config.suppression;
+                ui.selectable_value(suppression, Suppression::Mute, "Mute");
+                ui.selectable_value(suppression, Suppression::Spectral, "Spectral");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Attenuation");
+                ui.add(
+                    egui::Slider::new(&mut config.attenuation, MIN_ATTENUATION..=0.0)
+                        .text("dB")
+                        .fixed_decimals(1),
+                );
+                if config.attenuation <= MIN_ATTENUATION {
+                    ui.label("(muted)");
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Fade curve");
+                let curve = &mut config.delays.curve;
+                ui.selectable_value(curve, FadeCurve::Linear, "Linear");
+                ui.selectable_value(curve, FadeCurve::EqualPower, "Equal power");
+                ui.selectable_value(curve, FadeCurve::RaisedCosine, "Raised cosine");
+                ui.selectable_value(curve, FadeCurve::Exponential, "Exponential");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Trigger");
+                let mode = &mut config.trigger.mode;
+                ui.selectable_value(mode, TriggerMode::Evdev, "Input devices");
+                ui.selectable_value(mode, TriggerMode::Audio, "Audio");
+                ui.selectable_value(mode, TriggerMode::Both, "Both");
+                if *mode == TriggerMode::Evdev {
+                    ui.checkbox(&mut config.trigger.align, "Align to transients");
+                }
+                if config.trigger.uses_transients() {
+                    ui.add(
+                        egui::Slider::new(&mut config.trigger.threshold, 0.0..=40.0)
+                            .text("dB threshold")
+                            .fixed_decimals(1),
+                    );
+                }
+            });
             ui.separator();
 
             ui.horizontal(|ui| {
                 ui.selectable_value(plot_mode, PlotMode::NoView, "No view");
                 ui.selectable_value(plot_mode, PlotMode::LiveSignal, "Live signal");
                 ui.selectable_value(plot_mode, PlotMode::Capture, "Capture");
+                ui.selectable_value(plot_mode, PlotMode::Devices, "Devices");
+                ui.selectable_value(plot_mode, PlotMode::Calibration, "Calibrate");
+                ui.selectable_value(plot_mode, PlotMode::Background, "Background");
+                ui.selectable_value(plot_mode, PlotMode::Recording, "Record");
 
                 match *plot_mode {
                     PlotMode::LiveSignal => {
@@ -222,7 +468,11 @@ impl Stage {
                             *origo_at_click = !*origo_at_click;
                         }
                     }
-                    PlotMode::NoView => (),
+                    PlotMode::Devices
+                    | PlotMode::Calibration
+                    | PlotMode::Background
+                    | PlotMode::Recording
+                    | PlotMode::NoView => (),
                 }
                 if ui
                     .selectable_label(*autoscale_y, "Autoscale Y axis")
@@ -232,9 +482,104 @@ impl Stage {
                 }
             });
 
-            let click_info = click_info.lock().unwrap();
+            let mut click_info = click_info.lock().unwrap();
             match *plot_mode {
                 PlotMode::NoView => (),
+                PlotMode::Calibration => {
+                    let sample_rate = click_info.sample_rate;
+                    match click_info.calibration {
+                        Some(ref calibration) => {
+                            ui.label(format!(
+                                "Type normally while keeping otherwise silent: {:.0}% done, {} clicks",
+                                calibration.progress() * 100.0,
+                                calibration.num_clicks()
+                            ));
+                            if ui.button("Cancel").clicked() {
+                                click_info.calibration = None;
+                            }
+                        }
+                        None => {
+                            ui.label(format!(
+                                "Calibration finds the mute offset and duration from the sounds of \
+                                 your clicks. Press Start and type normally for {} seconds \
+                                 without other sounds around.",
+                                CALIBRATION_SECONDS
+                            ));
+                            if ui.button("Start").clicked() {
+                                click_info.calibration =
+                                    Some(Calibration::new(sample_rate, CALIBRATION_SECONDS));
+                                *calibration_result = None;
+                            }
+                            match calibration_result {
+                                None => (),
+                                Some(None) => {
+                                    ui.label("No click sounds could be found; is the right input connected?");
+                                }
+                                Some(Some(proposal)) => {
+                                    ui.separator();
+                                    ui.label(format!(
+                                        "Click sounds found in {} of {} captures",
+                                        proposal.num_used, proposal.num_captures
+                                    ));
+                                    ui.label(format!(
+                                        "Onset {:.3} s (standard deviation {:.3} s)",
+                                        proposal.onset.mean, proposal.onset.std_dev
+                                    ));
+                                    ui.label(format!(
+                                        "End {:.3} s (standard deviation {:.3} s)",
+                                        proposal.end.mean, proposal.end.std_dev
+                                    ));
+                                    ui.label(format!(
+                                        "Proposed mute offset {:.3} s, mute duration {:.3} s",
+                                        proposal.delays.mute_offset, proposal.delays.mute_duration
+                                    ));
+                                    ui.horizontal(|ui| {
+                                        let accept = ui.button("Accept").clicked();
+                                        let save = ui.button("Accept and save").clicked();
+                                        if accept || save {
+                                            config.delays.mute_offset =
+                                                proposal.delays.mute_offset;
+                                            config.delays.mute_duration =
+                                                proposal.delays.mute_duration;
+                                        }
+                                        if save {
+                                            if let Err(error) = config.save(config_file) {
+                                                println!("Failed to save config: {:?}", error);
+                                            }
+                                        }
+                                    });
+                                }
+                            }
+                        }
+                    }
+                }
+                PlotMode::Devices => {
+                    egui::ScrollArea::auto_sized().show(ui, |ui| {
+                        for device in click_info.devices.iter() {
+                            let mut enabled = device.enabled;
+                            if ui
+                                .checkbox(
+                                    &mut enabled,
+                                    format!("{} ({})", device.name, device.phys),
+                                )
+                                .on_hover_text(format!(
+                                    "vendor {:04x} product {:04x}",
+                                    device.vendor, device.product
+                                ))
+                                .changed()
+                            {
+                                config.devices.set_enabled(device, enabled);
+                            }
+                        }
+                    });
+                }
+                PlotMode::Background => {
+                    background_panel(ui, &mut config.background, &click_info, control);
+                }
+                PlotMode::Recording => {
+                    start_recording_clicked =
+                        recording_panel(ui, recording_taps, &mut click_info, control);
+                }
                 _ if !click_info.click_sampler.is_in_auto_hold()
                     && click_info.click_sampler.is_in_auto() => {}
                 _ => {
@@ -291,7 +636,17 @@ impl Stage {
                     }
                 }
             }
+
+            if *config != old_config {
+                click_info.config = config.clone();
+                control
+                    .send(click_mute_control::Message::UpdateConfig(Box::new(config.clone())))
+                    .unwrap();
+            }
         });
+        if start_recording_clicked {
+            start_recording(recording_taps, &click_info, control);
+        }
     }
 }
 
@@ -0,0 +1,128 @@
+use crate::click_info::ClickInfo;
+use crate::device_info::DeviceInfo;
+use crate::level_event::LevelEvent;
+use std::sync::{Arc, Mutex};
+use std::{mem, thread, time};
+
+// How often the state is checked for changes
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+// Signals handles SIGINT and SIGTERM by activating the exit flag, so that ClickMute can be shut
+// down cleanly
+pub struct Signals {
+    set: libc::sigset_t,
+}
+
+impl Signals {
+    /** Blocks SIGINT and SIGTERM in the calling thread and in the threads it starts afterwards, so
+     * that they are only seen by the thread started by activate_on_signal. Call this before
+     * starting any other threads. */
+    pub fn block() -> Signals {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            Signals { set }
+        }
+    }
+
+    // Starts a thread that activates exit when a signal arrives
+    pub fn activate_on_signal(self, mut exit: LevelEvent) {
+        thread::spawn(move || {
+            let mut signal: libc::c_int = 0;
+            if unsafe { libc::sigwait(&self.set, &mut signal) } == 0 {
+                eprintln!("Received signal {}, exiting", signal);
+            }
+            exit.activate();
+        });
+    }
+}
+
+// The state shown to the user in the GUI; in headless mode changes to this are logged
+#[derive(PartialEq)]
+struct State {
+    mute_enabled: bool,
+    invert_mute: bool,
+    background_noise: bool,
+    devices: Vec<DeviceInfo>,
+    recording: Option<String>,
+    recording_error: Option<String>,
+}
+
+impl State {
+    fn new(click_info: &ClickInfo) -> State {
+        State {
+            mute_enabled: click_info.mute_enabled,
+            invert_mute: click_info.invert_mute,
+            background_noise: click_info.background_noise,
+            devices: click_info.devices.clone(),
+            recording: click_info.recording.clone(),
+            recording_error: click_info.recording_error.clone(),
+        }
+    }
+
+    // Logs the differences between the previous state and this one
+    fn log_changes(&self, previous: Option<&State>) {
+        let on_off = |x: bool| if x { "on" } else { "off" };
+        if previous.map(|x| x.mute_enabled) != Some(self.mute_enabled) {
+            eprintln!("Automatic muting {}", on_off(self.mute_enabled));
+        }
+        if previous.map(|x| x.invert_mute) != Some(self.invert_mute) {
+            eprintln!("Inverted muting {}", on_off(self.invert_mute));
+        }
+        if previous.map(|x| x.background_noise) != Some(self.background_noise) {
+            eprintln!("Background noise {}", on_off(self.background_noise));
+        }
+        for device in self.devices.iter() {
+            let previous_device = previous.and_then(|x| {
+                x.devices
+                    .iter()
+                    .find(|x| x.phys == device.phys && x.name == device.name)
+            });
+            if previous_device.map(|x| x.enabled) != Some(device.enabled) {
+                eprintln!(
+                    "{} device {} ({})",
+                    if device.enabled { "Using" } else { "Ignoring" },
+                    device.name,
+                    device.phys
+                );
+            }
+        }
+        let previous_recording = previous.and_then(|x| x.recording.as_ref());
+        if previous_recording != self.recording.as_ref() {
+            if let Some(dir) = previous_recording {
+                eprintln!("Stopped recording to {}", dir);
+            }
+            if let Some(ref dir) = self.recording {
+                eprintln!("Recording to {}", dir);
+            }
+        }
+        if let Some(ref error) = self.recording_error {
+            if previous.and_then(|x| x.recording_error.as_ref()) != Some(error) {
+                eprintln!("{}", error);
+            }
+        }
+    }
+}
+
+// Runs until exit is activated, logging the state changes to stderr
+pub fn main(exit: LevelEvent, click_info: Arc<Mutex<ClickInfo>>) {
+    eprintln!("Running without GUI");
+    let mut previous: Option<State> = None;
+    loop {
+        let state = State::new(&click_info.lock().unwrap());
+        if previous.as_ref() != Some(&state) {
+            state.log_changes(previous.as_ref());
+            previous = Some(state);
+        }
+        if exit.wait_timeout(POLL_INTERVAL) {
+            break;
+        }
+    }
+    eprintln!(
+        "Exiting after {} clicks",
+        click_info.lock().unwrap().num_clicks
+    );
+}
@@ -0,0 +1,148 @@
+use crate::config;
+
+// Hotkeys keeps track of the keys of the push-to-talk and push-to-mute chords that are being held,
+// to find out whether the hotkeys mute the output
+pub struct Hotkeys {
+    push_to_talk: Vec<u16>,
+    push_to_mute: Vec<u16>,
+    held: Vec<u16>, // only the keys that are a part of a hotkey
+}
+
+impl Hotkeys {
+    // Unknown key names are ignored; they are reported when loading the configuration
+    pub fn new(config: &config::Hotkeys) -> Hotkeys {
+        let codes = |keys: &[config::Key]| keys.iter().filter_map(|x| x.code()).collect::<Vec<_>>();
+        let push_to_talk = codes(&config.push_to_talk);
+        let push_to_mute = codes(&config.push_to_mute);
+        let held = Vec::with_capacity(push_to_talk.len() + push_to_mute.len());
+        Hotkeys {
+            push_to_talk,
+            push_to_mute,
+            held,
+        }
+    }
+
+    fn is_hotkey(&self, code: u16) -> bool {
+        self.push_to_talk.contains(&code) || self.push_to_mute.contains(&code)
+    }
+
+    /** Updates the held keys with an evdev key event. Returns whether the event is a part of using
+     * a hotkey, in which case it is not a click: it is a key of a chord that completes or releases
+     * the chord, or repeats while the chord is held. The keys of a chord pressed on their own are
+     * clicks like any other keys. */
+    pub fn key_event(&mut self, code: u16, value: i32) -> bool {
+        if !self.is_hotkey(code) {
+            return false;
+        }
+        let was_held = self.is_any_held();
+        match value {
+            0 => self.held.retain(|x| *x != code),
+            1 if !self.held.contains(&code) => self.held.push(code),
+            _ => (), // key repeat
+        }
+        was_held || self.is_any_held()
+    }
+
+    fn is_held(&self, chord: &[u16]) -> bool {
+        !chord.is_empty() && chord.iter().all(|x| self.held.contains(x))
+    }
+
+    fn is_any_held(&self) -> bool {
+        self.is_held(&self.push_to_talk) || self.is_held(&self.push_to_mute)
+    }
+
+    // Whether the output should be muted: push-to-talk mutes unless it is held, and push-to-mute
+    // mutes while it is held
+    pub fn is_muted(&self) -> bool {
+        (!self.push_to_talk.is_empty() && !self.is_held(&self.push_to_talk))
+            || self.is_held(&self.push_to_mute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Key;
+
+    const KEY_LEFTCTRL: u16 = 29;
+    const KEY_SPACE: u16 = 57;
+    const KEY_A: u16 = 30;
+
+    // push-to-mute with Ctrl+Space
+    fn hotkeys() -> Hotkeys {
+        Hotkeys::new(&config::Hotkeys {
+            push_to_talk: vec![],
+            push_to_mute: vec![
+                Key::Name(String::from("KEY_LEFTCTRL")),
+                Key::Code(KEY_SPACE),
+            ],
+        })
+    }
+
+    #[test]
+    fn chord_press() {
+        let mut hotkeys = hotkeys();
+        assert!(!hotkeys.key_event(KEY_LEFTCTRL, 1));
+        assert!(!hotkeys.is_muted());
+        assert!(hotkeys.key_event(KEY_SPACE, 1));
+        assert!(hotkeys.is_muted());
+    }
+
+    #[test]
+    fn solo_press() {
+        let mut hotkeys = hotkeys();
+        assert!(!hotkeys.key_event(KEY_SPACE, 1));
+        assert!(!hotkeys.key_event(KEY_SPACE, 2));
+        assert!(!hotkeys.key_event(KEY_SPACE, 0));
+        assert!(!hotkeys.is_muted());
+    }
+
+    #[test]
+    fn partial_release() {
+        let mut hotkeys = hotkeys();
+        hotkeys.key_event(KEY_LEFTCTRL, 1);
+        hotkeys.key_event(KEY_SPACE, 1);
+        // releasing the first key releases the chord
+        assert!(hotkeys.key_event(KEY_SPACE, 0));
+        assert!(!hotkeys.is_muted());
+        assert!(!hotkeys.key_event(KEY_LEFTCTRL, 0));
+        // pressing the key again completes the chord again
+        hotkeys.key_event(KEY_LEFTCTRL, 1);
+        assert!(hotkeys.key_event(KEY_SPACE, 1));
+        assert!(hotkeys.is_muted());
+    }
+
+    #[test]
+    fn key_repeat() {
+        let mut hotkeys = hotkeys();
+        hotkeys.key_event(KEY_LEFTCTRL, 1);
+        hotkeys.key_event(KEY_SPACE, 1);
+        assert!(hotkeys.key_event(KEY_SPACE, 2));
+        assert!(hotkeys.key_event(KEY_LEFTCTRL, 2));
+        assert!(hotkeys.is_muted());
+    }
+
+    #[test]
+    fn other_key_while_held() {
+        let mut hotkeys = hotkeys();
+        hotkeys.key_event(KEY_LEFTCTRL, 1);
+        hotkeys.key_event(KEY_SPACE, 1);
+        // the other keys are clicks, and don't change the muting
+        assert!(!hotkeys.key_event(KEY_A, 1));
+        assert!(!hotkeys.key_event(KEY_A, 0));
+        assert!(hotkeys.is_muted());
+    }
+
+    #[test]
+    fn push_to_talk() {
+        let mut hotkeys = Hotkeys::new(&config::Hotkeys {
+            push_to_talk: vec![Key::Code(KEY_SPACE)],
+            push_to_mute: vec![],
+        });
+        assert!(hotkeys.is_muted());
+        assert!(hotkeys.key_event(KEY_SPACE, 1));
+        assert!(!hotkeys.is_muted());
+        assert!(hotkeys.key_event(KEY_SPACE, 0));
+        assert!(hotkeys.is_muted());
+    }
+}
@@ -1,45 +1,104 @@
-use crate::background_sampler;
 use crate::background_sampler::BackgroundSampler;
+use crate::fade_curve::FadeCurve;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// The clips are noise, so they are cross-faded keeping their summed power constant
+const CROSS_FADE_CURVE: FadeCurve = FadeCurve::EqualPower;
 
 struct PlayClip {
-    sample_a: Vec<f32>,
-    sample_b: Vec<f32>,
+    channels: Vec<Vec<f32>>,
+    start: usize, // the random offset the clip is played from
     playhead: usize,
+    overlap: usize, // the length of the fade in and out
+}
+
+impl PlayClip {
+    fn len(&self) -> usize {
+        self.channels[0].len()
+    }
+
+    fn remaining(&self) -> usize {
+        self.len() - self.playhead
+    }
+
+    // The gain of the fades at the start and the end of the clip; the clips overlap so that the
+    // position of the fade in of a clip and the fade out of the previous one add up to overlap
+    fn gain(&self) -> f32 {
+        let fade = usize::min(self.playhead - self.start, self.remaining());
+        if fade >= self.overlap {
+            1.0
+        } else {
+            CROSS_FADE_CURVE.gain(fade as f32 / self.overlap as f32, 0.0)
+        }
+    }
 }
 
+// Looper plays random clips out of the quietest ones of the background sampler one after another,
+// each from a random offset, and overlap-adds them so that there are no gaps or discontinuities
+// between them
 pub struct Looper {
-    current_clip: Option<PlayClip>,
+    overlap: usize,
+    playing: Vec<PlayClip>, // the clip fading out, if any, and the current one
+    rng: StdRng,
 }
 
 impl Looper {
-    pub fn new() -> Looper {
-        Looper { current_clip: None }
+    pub fn new(overlap: usize) -> Looper {
+        Looper {
+            overlap,
+            playing: Vec::with_capacity(2),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    pub fn set_overlap(&mut self, overlap: usize) {
+        self.overlap = overlap;
     }
 
+    fn start_clip(&mut self, background_sampler: &mut BackgroundSampler, num_quietest: usize) {
+        if let Some(clip) = background_sampler.choose_clip(num_quietest) {
+            let channels: Vec<Vec<f32>> = clip.channels.iter().map(|x| x.get()).collect();
+            let len = channels[0].len();
+            if len == 0 {
+                return;
+            }
+            let overlap = usize::min(self.overlap, len / 2);
+            // leave at least half of the clip to play, and room for both of the fades
+            let max_offset = usize::min(len / 2, len - 2 * overlap);
+            let start = self.rng.gen_range(0..=max_offset);
+            self.playing.push(PlayClip {
+                channels,
+                start,
+                playhead: start,
+                overlap,
+            });
+        }
+    }
+
+    // Produces the next frame of background noise to frame, with a sample for each channel, from
+    // the num_quietest quietest clips
     pub fn produce(
         &mut self,
         background_sampler: &mut BackgroundSampler,
-    ) -> background_sampler::StereoSample {
-        if self.current_clip.is_none() {
-            if let Some(clip) = background_sampler.choose_clip(10) {
-                self.current_clip = Some(PlayClip {
-                    sample_a: clip.sample_a.get(),
-                    sample_b: clip.sample_b.get(),
-                    playhead: 0,
-                })
-            }
+        num_quietest: usize,
+        frame: &mut [f32],
+    ) {
+        let start = match self.playing.last() {
+            None => true,
+            Some(clip) => clip.remaining() <= clip.overlap,
+        };
+        if start {
+            self.start_clip(background_sampler, num_quietest);
         }
-        if let Some(ref mut clip) = self.current_clip {
-            if clip.playhead >= clip.sample_a.len() {
-                self.current_clip = None;
-                (0.0, 0.0)
-            } else {
-                let sample = (clip.sample_a[clip.playhead], clip.sample_b[clip.playhead]);
-                clip.playhead += 1;
-                sample
+        frame.iter_mut().for_each(|x| *x = 0.0);
+        for clip in self.playing.iter_mut() {
+            let gain = clip.gain();
+            for (sample, channel) in frame.iter_mut().zip(clip.channels.iter()) {
+                *sample += channel[clip.playhead] * gain;
             }
-        } else {
-            (0.0, 0.0)
+            clip.playhead += 1;
         }
+        self.playing.retain(|clip| clip.remaining() > 0);
     }
 }
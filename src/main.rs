@@ -1,23 +1,42 @@
+mod auto_connect;
 mod background_sampler;
+mod calibration;
 mod click_info;
 mod click_mute;
 mod click_mute_control;
+mod click_source;
 mod clicky_events;
+mod comfort_noise;
 mod config;
+mod control_socket;
 mod cross_fader;
 mod delay;
+mod device_info;
 mod error;
+mod event_log;
+mod fade_curve;
 mod fader;
+mod fft;
 mod gui;
+mod headless;
+mod hotkeys;
+mod key_names;
 mod level_event;
 mod looper;
 mod measure;
+mod offline;
+mod recording;
 mod sampler;
-mod save;
+mod spectral_suppressor;
+mod transient_detector;
+mod voice_activity;
 
 use crate::click_info::ClickInfo;
+use crate::click_source::ClickSource;
+use crate::clicky_events::ClickyEvents;
 use crate::config::Config;
 use crate::level_event::LevelEvent;
+use crate::recording::Tap;
 use clap::{App, Arg};
 use directories::ProjectDirs;
 use std::path::Path;
@@ -66,7 +85,16 @@ fn get_config_file(config_file_arg: Option<&str>) -> Result<String, error::Error
     Ok(config_file.to_string())
 }
 
+// A file in the XDG data directory, if the directory can be created
+fn get_data_file(name: &str) -> Option<String> {
+    let proj_dirs = ProjectDirs::from("", "Erkki Seppälä", "click_mute")?;
+    let data_dir = proj_dirs.data_dir();
+    std::fs::create_dir_all(data_dir).ok()?;
+    data_dir.join(name).to_str().map(String::from)
+}
+
 fn main() -> Result<(), error::Error> {
+    let tap_names: Vec<&str> = Tap::ALL.iter().map(Tap::name).collect();
     let args = App::new("click_mute")
         .version(option_env!("GIT_DESCRIBE").unwrap_or_else(|| env!("VERGEN_SEMVER")))
         .author("Erkki Seppälä <erkki.seppala@vincit.fi>")
@@ -78,6 +106,92 @@ fn main() -> Result<(), error::Error> {
                 .takes_value(true)
                 .about("Configuration file to load (and save, if the save function is used)"),
         )
+        .arg(Arg::new("headless").long("headless").about(
+            "Run without the GUI, logging state changes to stderr; exit with SIGINT or SIGTERM",
+        ))
+        .arg(
+            Arg::new("control-socket")
+                .long("control-socket")
+                .takes_value(true)
+                .about("Listen to control commands in this Unix domain socket"),
+        )
+        .arg(
+            Arg::new("record-events")
+                .long("record-events")
+                .takes_value(true)
+                .about("Record all key and wheel events to this event log file"),
+        )
+        .arg(
+            Arg::new("replay-events")
+                .long("replay-events")
+                .takes_value(true)
+                .conflicts_with("record-events")
+                .about("Use the events from this event log instead of the input devices"),
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .about("Record the processing stages to WAV files from the start"),
+        )
+        .arg(
+            Arg::new("record-taps")
+                .long("record-taps")
+                .takes_value(true)
+                .use_delimiter(true)
+                .possible_values(&tap_names)
+                .requires("record")
+                .about("Comma-separated stages to record; all of them by default"),
+        )
+        .arg(
+            Arg::new("record-dir")
+                .long("record-dir")
+                .takes_value(true)
+                .about("Directory to create the recordings in"),
+        )
+        .subcommand(
+            App::new("process")
+                .about("Process a WAV file with a recorded event log instead of running live")
+                .arg(
+                    Arg::new("input")
+                        .long("input")
+                        .short('i')
+                        .takes_value(true)
+                        .required(true)
+                        .about("WAV file to process (mono, or as many channels as configured)"),
+                )
+                .arg(
+                    Arg::new("events")
+                        .long("events")
+                        .short('e')
+                        .takes_value(true)
+                        .required(true)
+                        .about("Event log to use for the clicks"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .takes_value(true)
+                        .required(true)
+                        .about("WAV file to write the output to"),
+                )
+                .arg(
+                    Arg::new("start-time")
+                        .long("start-time")
+                        .takes_value(true)
+                        .about(
+                            "Event log time corresponding to the beginning of the input; \
+                             the time of the first event by default",
+                        ),
+                )
+                .arg(
+                    Arg::new("block-size")
+                        .long("block-size")
+                        .takes_value(true)
+                        .default_value("128")
+                        .about("Number of samples to process at a time"),
+                ),
+        )
         .get_matches();
     let config_file = get_config_file(args.value_of("config"))?;
     let (send_control, recv_control) = mpsc::channel();
@@ -89,32 +203,114 @@ fn main() -> Result<(), error::Error> {
         }
         Err(err) => return Err(error::Error::ConfigError(err)),
     };
+    if let Some(("process", args)) = args.subcommand() {
+        let args = offline::Args {
+            input: args.value_of("input").unwrap().to_string(),
+            events: args.value_of("events").unwrap().to_string(),
+            output: args.value_of("output").unwrap().to_string(),
+            start_time: if args.is_present("start-time") {
+                Some(args.value_of_t_or_exit("start-time"))
+            } else {
+                None
+            },
+            block_size: args.value_of_t_or_exit("block-size"),
+        };
+        offline::main(config, args)?;
+        return Ok(());
+    }
+    // signals need to be blocked before any threads are started
+    let signals = if args.is_present("headless") {
+        Some(headless::Signals::block())
+    } else {
+        None
+    };
+    let record = if args.is_present("record") {
+        Some(match args.values_of("record-taps") {
+            Some(names) => names.filter_map(Tap::from_name).collect(),
+            None => Tap::ALL.to_vec(),
+        })
+    } else {
+        None
+    };
+    let click_info = Arc::new(Mutex::new(ClickInfo::new(config.clone())));
+    if let Some(dir) = args
+        .value_of("record-dir")
+        .map(String::from)
+        .or_else(|| get_data_file("recordings"))
+    {
+        click_info.lock().unwrap().recording_dir = dir;
+    }
+    let (events, _control_socket) = match args.value_of("control-socket") {
+        Some(path) => {
+            let (send_events, recv_events) =
+                crossbeam_channel::bounded(control_socket::EVENT_QUEUE_SIZE);
+            let control_socket = control_socket::ControlSocket::new(
+                path,
+                click_info.clone(),
+                send_control.clone(),
+                recv_events,
+            )?;
+            (Some(send_events), Some(control_socket))
+        }
+        None => (None, None),
+    };
+    let click_source: Box<dyn ClickSource> = match args.value_of("replay-events") {
+        Some(filename) => {
+            let mut replay = event_log::Replay::load(filename)?;
+            replay.update_config(&config);
+            Box::new(event_log::LiveReplay::new(replay))
+        }
+        None => {
+            let recorder = match args.value_of("record-events") {
+                Some(filename) => Some(event_log::Recorder::new(filename)?),
+                None => None,
+            };
+            Box::new(ClickyEvents::new(&config, click_info.clone(), recorder))
+        }
+    };
     let exit_flag = LevelEvent::new();
-    let click_info = Arc::new(Mutex::new(ClickInfo::new()));
-    let gui_join = {
+    let ui_join = match signals {
+        Some(signals) => {
+            signals.activate_on_signal(exit_flag.clone());
+            let exit_flag = exit_flag.clone();
+            let click_info = click_info.clone();
+            thread::spawn(move || headless::main(exit_flag, click_info))
+        }
+        None => {
+            let mut exit_flag = exit_flag.clone();
+            let click_info = click_info.clone();
+            let config = config.clone();
+            thread::spawn(move || {
+                gui::main(
+                    exit_flag.clone(),
+                    click_info,
+                    config,
+                    config_file,
+                    send_control,
+                );
+                exit_flag.activate();
+            })
+        }
+    };
+    let click_mute_join = {
         let mut exit_flag = exit_flag.clone();
-        let click_info = click_info.clone();
         thread::spawn(move || {
-            gui::main(
+            let result = click_mute::main(
                 exit_flag.clone(),
                 click_info,
                 config,
-                config_file,
-                send_control,
+                recv_control,
+                click_source,
+                events,
+                get_data_file("background_clips.bin"),
+                record,
             );
             exit_flag.activate();
-        })
-    };
-    let click_mute_join = {
-        let mut exit_flag = exit_flag.clone();
-        thread::spawn(move || {
-            let result = click_mute::main(exit_flag.clone(), click_info, config, recv_control);
-            exit_flag.activate();
             result
         })
     };
     exit_flag.wait();
     click_mute_join.join().unwrap()?;
-    gui_join.join().unwrap();
+    ui_join.join().unwrap();
     Ok(())
 }
@@ -0,0 +1,134 @@
+use crate::click_info::ClickInfo;
+use crate::click_mute::ClickMute;
+use crate::config::Config;
+use crate::event_log;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    EventLogError(#[from] event_log::Error),
+
+    #[error(transparent)]
+    WavError(#[from] hound::Error),
+
+    #[error("input has {0} channels, but {1} are configured; only mono input can be used for more channels")]
+    UnsupportedChannels(u16, usize),
+}
+
+pub struct Args {
+    pub input: String,
+    pub events: String,
+    pub output: String,
+    // the time in the event log that corresponds to the first sample of the input; the time of
+    // the first event if not given
+    pub start_time: Option<f64>,
+    // how many samples to process at a time, like the Jack period size
+    pub block_size: usize,
+}
+
+fn to_f32(sample: i32, bits_per_sample: u16) -> f32 {
+    sample as f32 / (1_i64 << (bits_per_sample - 1)) as f32
+}
+
+fn read_wav(filename: &str) -> Result<(hound::WavSpec, Vec<f32>), Error> {
+    let mut reader = hound::WavReader::open(filename)?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => reader
+            .samples::<i32>()
+            .map(|x| x.map(|x| to_f32(x, spec.bits_per_sample)))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    Ok((spec, samples))
+}
+
+// Runs the muting pipeline for the input file and the event log, writing the result to output
+pub fn main(config: Config, args: Args) -> Result<(), Error> {
+    let (spec, samples) = read_wav(&args.input)?;
+    let num_channels = config.channels;
+    // mono input is used for all the channels
+    let inputs: Vec<Vec<f32>> = match spec.channels as usize {
+        1 => vec![samples; num_channels],
+        channels if channels == num_channels => (0..num_channels)
+            .map(|channel| {
+                samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .cloned()
+                    .collect()
+            })
+            .collect(),
+        channels => return Err(Error::UnsupportedChannels(channels as u16, num_channels)),
+    };
+    let sample_rate = spec.sample_rate as usize;
+
+    let mut replay = event_log::Replay::load(&args.events)?;
+    replay.update_config(&config);
+    let start_time = match args.start_time {
+        Some(start_time) => start_time,
+        None => {
+            let start_time = replay.start_time().unwrap_or(0.0);
+            eprintln!(
+                "Taking the events at {:.6} to be at the beginning of {}; use --start-time to \
+                 change this",
+                start_time, args.input
+            );
+            start_time
+        }
+    };
+
+    let click_info = Arc::new(Mutex::new(ClickInfo::new(config.clone())));
+    let mut click_mute = ClickMute::new(sample_rate, click_info, config.clone(), None);
+
+    // flush the delay buffer at the end, and leave out the delay at the start, so that the output
+    // lines up with the input
+    let delay_samples = click_mute.delay_samples();
+    let num_samples = inputs[0].len() + delay_samples;
+    let padded = |x: &[f32], from: usize, to: usize| -> Vec<f32> {
+        (from..to)
+            .map(|index| if index < x.len() { x[index] } else { 0.0 })
+            .collect()
+    };
+
+    let mut writer = hound::WavWriter::create(
+        &args.output,
+        hound::WavSpec {
+            channels: num_channels as u16,
+            sample_rate: spec.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        },
+    )?;
+
+    let block_size = usize::max(1, args.block_size);
+    let mut outputs = vec![vec![0.0f32; block_size]; num_channels];
+    let mut clicks = vec![];
+    for from in (0..num_samples).step_by(block_size) {
+        let to = usize::min(num_samples, from + block_size);
+        let blocks: Vec<Vec<f32>> = inputs.iter().map(|x| padded(x, from, to)).collect();
+        let now = start_time + from as f64 / sample_rate as f64;
+        clicks.clear();
+        replay.when_clicked(now, &mut clicks);
+        click_mute.process(
+            &clicks,
+            replay.is_hotkey_muted(),
+            &blocks.iter().map(|x| x.as_slice()).collect::<Vec<_>>(),
+            &mut outputs
+                .iter_mut()
+                .map(|x| &mut x[0..to - from])
+                .collect::<Vec<_>>(),
+        );
+        for index in delay_samples.saturating_sub(from)..to - from {
+            for output in outputs.iter() {
+                writer.write_sample(output[index])?;
+            }
+        }
+    }
+    writer.finalize()?;
+
+    Ok(())
+}
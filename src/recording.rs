@@ -0,0 +1,306 @@
+use crate::click_info::ClickInfo;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use thiserror::Error;
+
+// How many frames are passed to the writer at a time, and how many such blocks may be waiting to
+// be written for all the taps before the samples are dropped
+const BLOCK_FRAMES: usize = 4096;
+const NUM_BLOCKS: usize = 64;
+
+// The points of the processing that can be recorded, each to a WAV file of its own
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Tap {
+    Input,      // the input, as it arrives
+    Delayed,    // the input after the delay
+    Output,     // the output
+    Gate,       // 1.0 when muting, otherwise 0.0
+    Background, // the background noise
+}
+
+impl Tap {
+    pub const ALL: [Tap; 5] = [
+        Tap::Input,
+        Tap::Delayed,
+        Tap::Output,
+        Tap::Gate,
+        Tap::Background,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tap::Input => "input",
+            Tap::Delayed => "delayed",
+            Tap::Output => "output",
+            Tap::Gate => "gate",
+            Tap::Background => "background",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Tap> {
+        Tap::ALL.iter().copied().find(|x| x.name() == name)
+    }
+
+    fn num_channels(&self, num_channels: usize) -> usize {
+        match self {
+            Tap::Gate => 1,
+            _ => num_channels,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("already recording to {0}")]
+    AlreadyRecording(String),
+
+    #[error("no taps to record")]
+    NoTaps,
+
+    #[error("the recording fell behind, and {0} samples were dropped")]
+    Overrun(usize),
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+
+    #[error(transparent)]
+    WavError(#[from] hound::Error),
+}
+
+// The samples of a tap, interleaved
+type Block = (usize, Vec<f32>);
+
+// Recorder is the realtime half of a recording: it collects the samples of the taps in ClickMute
+// and passes them in blocks to the writer thread. The blocks are recycled, so recording doesn't
+// allocate; if the writer falls behind, the samples are dropped instead.
+pub struct Recorder {
+    tap_indices: [Option<usize>; Tap::ALL.len()], // the index of each tap recorded in buffers
+    channels: Vec<usize>,                         // the number of channels of each of them
+    buffers: Vec<Vec<f32>>,
+    blocks: crossbeam_channel::Sender<Block>,
+    free_blocks: crossbeam_channel::Receiver<Vec<f32>>,
+    dropped: Arc<AtomicUsize>, // samples
+    failed: bool,              // the writer has stopped
+    writer: thread::JoinHandle<()>,
+}
+
+impl Recorder {
+    // Passes the buffer of the tap to the writer, replacing it with a free block
+    fn flush(&mut self, index: usize) {
+        let buffer = &mut self.buffers[index];
+        match self.free_blocks.try_recv() {
+            Ok(free_block) => {
+                let block = mem::replace(buffer, free_block);
+                if self.blocks.try_send((index, block)).is_err() {
+                    self.failed = true;
+                }
+            }
+            Err(crossbeam_channel::TryRecvError::Empty) => {
+                self.dropped.fetch_add(buffer.len(), Ordering::Relaxed);
+                buffer.clear();
+            }
+            Err(crossbeam_channel::TryRecvError::Disconnected) => self.failed = true,
+        }
+    }
+
+    // Adds the samples of the next frame of the tap, if it's being recorded
+    pub fn record<I: Iterator<Item = f32>>(&mut self, tap: Tap, frame: I) {
+        let index = match self.tap_indices[tap as usize] {
+            Some(index) => index,
+            None => return,
+        };
+        if self.buffers[index].len() + self.channels[index] > self.buffers[index].capacity() {
+            self.flush(index);
+        }
+        self.buffers[index].extend(frame);
+    }
+
+    // Whether the writer has stopped, such as due to an error, so recording can be stopped
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    // Passes the samples collected so far to the writer; the recording is finished when the
+    // Recorder is dropped
+    pub fn finish(&mut self) {
+        for (index, buffer) in self.buffers.iter_mut().enumerate() {
+            if !buffer.is_empty() {
+                let _ = self.blocks.try_send((index, mem::take(buffer)));
+            }
+        }
+    }
+
+    // Drops the Recorder and waits for the files to be finished, such as before exiting. Not to
+    // be called from the realtime thread.
+    pub fn wait(self) {
+        let Recorder { blocks, writer, .. } = self;
+        drop(blocks);
+        let _ = writer.join();
+    }
+}
+
+type WavWriter = hound::WavWriter<io::BufWriter<fs::File>>;
+
+fn write_blocks(
+    mut writers: Vec<WavWriter>,
+    blocks: crossbeam_channel::Receiver<Block>,
+    free_blocks: crossbeam_channel::Sender<Vec<f32>>,
+    dropped: Arc<AtomicUsize>,
+) -> Result<(), Error> {
+    for (index, mut block) in blocks.iter() {
+        for sample in block.iter() {
+            writers[index].write_sample(*sample)?;
+        }
+        block.clear();
+        // the partial blocks passed by finish are extra, and dropped here
+        let _ = free_blocks.try_send(block);
+    }
+    for writer in writers {
+        writer.finalize()?;
+    }
+    match dropped.load(Ordering::Relaxed) {
+        0 => Ok(()),
+        dropped => Err(Error::Overrun(dropped)),
+    }
+}
+
+// The local time, for naming the recordings
+fn timestamp() -> String {
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        libc::localtime_r(&now, &mut tm);
+    }
+    format!(
+        "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+// Creates a new directory for a recording in base_dir, named by the time
+fn create_dir(base_dir: &str) -> Result<String, Error> {
+    fs::create_dir_all(base_dir)?;
+    let timestamp = timestamp();
+    let mut suffix = 1;
+    loop {
+        let name = if suffix == 1 {
+            timestamp.clone()
+        } else {
+            format!("{}-{}", timestamp, suffix)
+        };
+        let dir = Path::new(base_dir).join(name);
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir.to_string_lossy().into_owned()),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+            Err(error) => return Err(Error::IOError(error)),
+        }
+    }
+}
+
+/** Starts recording the taps to WAV files in a new directory under click_info.recording_dir, at
+ * the sample rate and with the channels of ClickMute. The files are created without holding the
+ * lock of click_info, and written in a thread of their own, which reports the errors to
+ * click_info. The returned Recorder is to be given to ClickMute, such as with
+ * click_mute_control::Message::StartRecording. */
+pub fn start(shared: &Arc<Mutex<ClickInfo>>, taps: &[Tap]) -> Result<Recorder, Error> {
+    if taps.is_empty() {
+        return Err(Error::NoTaps);
+    }
+    let (recording_dir, sample_rate, num_channels) = {
+        let click_info = shared.lock().unwrap();
+        if let Some(ref dir) = click_info.recording {
+            return Err(Error::AlreadyRecording(dir.clone()));
+        }
+        (
+            click_info.recording_dir.clone(),
+            click_info.sample_rate,
+            click_info.config.channels,
+        )
+    };
+    let dir = create_dir(&recording_dir)?;
+
+    // all the blocks are large enough for any of the taps
+    let block_size = BLOCK_FRAMES * num_channels;
+    let mut tap_indices = [None; Tap::ALL.len()];
+    let mut channels = vec![];
+    let mut writers = vec![];
+    let mut buffers = vec![];
+    for tap in taps {
+        if tap_indices[*tap as usize].is_some() {
+            continue;
+        }
+        tap_indices[*tap as usize] = Some(writers.len());
+        channels.push(tap.num_channels(num_channels));
+        writers.push(hound::WavWriter::create(
+            Path::new(&dir).join(format!("{}.wav", tap.name())),
+            hound::WavSpec {
+                channels: tap.num_channels(num_channels) as u16,
+                sample_rate: sample_rate as u32,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            },
+        )?);
+        buffers.push(Vec::with_capacity(block_size));
+    }
+
+    let (blocks_sender, blocks) = crossbeam_channel::bounded(NUM_BLOCKS + buffers.len());
+    let (free_blocks_sender, free_blocks) = crossbeam_channel::bounded(NUM_BLOCKS);
+    for _ in 0..NUM_BLOCKS {
+        free_blocks_sender
+            .send(Vec::with_capacity(block_size))
+            .unwrap();
+    }
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let dropped = dropped.clone();
+        let shared = shared.clone();
+        let dir = dir.clone();
+        thread::spawn(move || {
+            let result = write_blocks(writers, blocks, free_blocks_sender, dropped);
+            let mut click_info = shared.lock().unwrap();
+            if let Err(error) = result {
+                click_info.recording_error = Some(format!("Recording to {}: {}", dir, error));
+                // the recording stops with the error
+                if click_info.recording.as_ref() == Some(&dir) {
+                    click_info.recording = None;
+                }
+            }
+        })
+    };
+
+    let recorder = Recorder {
+        tap_indices,
+        channels,
+        buffers,
+        blocks: blocks_sender,
+        free_blocks,
+        dropped,
+        failed: false,
+        writer,
+    };
+
+    let mut click_info = shared.lock().unwrap();
+    if let Some(ref other_dir) = click_info.recording {
+        // another recording was started meanwhile, so this one is discarded
+        let other_dir = other_dir.clone();
+        drop(click_info);
+        recorder.wait();
+        let _ = fs::remove_dir_all(&dir);
+        return Err(Error::AlreadyRecording(other_dir));
+    }
+    click_info.recording = Some(dir);
+    click_info.recording_error = None;
+    Ok(recorder)
+}
@@ -0,0 +1,105 @@
+use crate::fft::{self, Complex, Fft};
+
+// The length of the analysis frames, which is also the latency of the processing, and how often
+// a frame is processed; ClickMute feeds the suppressor this much ahead of the output
+pub const FRAME_SIZE: usize = 512;
+const HOP_SIZE: usize = FRAME_SIZE / 4;
+
+// Time constant of the reference spectrum, which is followed outside the suppressed frames
+const REFERENCE_SECONDS: f64 = 0.05;
+
+// The bins this many times above the reference magnitude are dominated by the click
+const CLICK_RATIO: f32 = 2.0;
+
+// SpectralSuppressor attenuates clicks with a short-time Fourier transform: in the frames marked
+// for suppression, the frequency bins rising well above the spectrum preceding them are brought
+// back down to it, which leaves the sounds that were already going on, such as voiced speech,
+// mostly in place. The output is delayed by FRAME_SIZE samples.
+pub struct SpectralSuppressor {
+    fft: Fft,
+    window: Vec<f32>, // sqrt-Hann, used both for analysis and synthesis
+
+    // ring buffers of the last FRAME_SIZE input samples, whether they are to be suppressed, and
+    // the overlap-added output
+    input: Vec<f32>,
+    suppress: Vec<bool>,
+    output: Vec<f32>,
+    position: usize, // where in the ring buffers the next sample goes
+    hop_position: usize,
+
+    spectrum: Vec<Complex>,
+    reference: Vec<f32>, // the magnitudes of the bins outside the suppressed frames
+    reference_coeff: f32,
+    floor: f32, // the lowest gain applied to a bin
+}
+
+impl SpectralSuppressor {
+    pub fn new(sample_rate: usize) -> SpectralSuppressor {
+        let hop_seconds = HOP_SIZE as f64 / sample_rate as f64;
+        SpectralSuppressor {
+            fft: Fft::new(FRAME_SIZE),
+            window: fft::sqrt_hann(FRAME_SIZE),
+            input: vec![0.0; FRAME_SIZE],
+            suppress: vec![false; FRAME_SIZE],
+            output: vec![0.0; FRAME_SIZE],
+            position: 0,
+            hop_position: 0,
+            spectrum: vec![Complex::default(); FRAME_SIZE],
+            reference: vec![0.0; FRAME_SIZE / 2 + 1],
+            reference_coeff: (1.0 - f64::exp(-hop_seconds / REFERENCE_SECONDS)) as f32,
+            floor: 0.0,
+        }
+    }
+
+    pub fn set_floor(&mut self, floor: f32) {
+        self.floor = floor;
+    }
+
+    fn process_frame(&mut self) {
+        // the oldest sample of the frame is the next one to be overwritten
+        let position = self.position;
+        let frame_index = |index: usize| (position + index) % FRAME_SIZE;
+        for (index, bin) in self.spectrum.iter_mut().enumerate() {
+            *bin = Complex::new(self.input[frame_index(index)] * self.window[index], 0.0);
+        }
+        self.fft.forward(&mut self.spectrum);
+
+        let suppress = self.suppress[frame_index(FRAME_SIZE / 2)];
+        for bin in 0..=FRAME_SIZE / 2 {
+            let magnitude = self.spectrum[bin].norm();
+            let reference = &mut self.reference[bin];
+            if !suppress {
+                *reference += (magnitude - *reference) * self.reference_coeff;
+            } else if magnitude > *reference * CLICK_RATIO {
+                let gain = f32::max(self.floor, *reference / magnitude);
+                self.spectrum[bin] = self.spectrum[bin].scale(gain);
+                if bin != 0 && bin != FRAME_SIZE / 2 {
+                    // the mirrored bin of the real signal
+                    self.spectrum[FRAME_SIZE - bin] = self.spectrum[FRAME_SIZE - bin].scale(gain);
+                }
+            }
+        }
+
+        self.fft.inverse(&mut self.spectrum);
+        // the squared windows overlapping by HOP_SIZE add up to FRAME_SIZE / HOP_SIZE / 2
+        let scale = 2.0 * HOP_SIZE as f32 / FRAME_SIZE as f32;
+        for index in 0..FRAME_SIZE {
+            self.output[frame_index(index)] += self.spectrum[index].re * self.window[index] * scale;
+        }
+    }
+
+    // Returns the output for the sample FRAME_SIZE samples ago
+    pub fn process(&mut self, sample: f32, suppress: bool) -> f32 {
+        let output = self.output[self.position];
+        self.output[self.position] = 0.0;
+        self.input[self.position] = sample;
+        self.suppress[self.position] = suppress;
+        self.position = (self.position + 1) % FRAME_SIZE;
+        self.hop_position += 1;
+        if self.hop_position == HOP_SIZE {
+            self.hop_position = 0;
+            self.process_frame();
+        }
+        output
+    }
+}
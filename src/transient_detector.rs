@@ -0,0 +1,71 @@
+// How long after the start of a transient it is detected, roughly; the onsets reported are this
+// much earlier than the detection
+pub const LATENCY_SECONDS: f64 = 0.002;
+
+// Time constants of the envelopes of the short-term and the background high frequency energy
+const FAST_SECONDS: f64 = 0.001;
+const SLOW_SECONDS: f64 = 0.1;
+
+// After a transient, no new ones are detected for this long
+const HOLDOFF_SECONDS: f64 = 0.05;
+
+// Transients quieter than this (-60 dBFS) are ignored, so that silence isn't full of them
+const MIN_ENERGY: f32 = 1e-6;
+
+// TransientDetector finds onsets of clicks, knocks and such in audio by comparing the short-term
+// high frequency energy of the signal to its background level
+pub struct TransientDetector {
+    previous: f32, // the previous sample, for the high-pass filter
+    fast: f32,     // the envelopes of the high frequency energy
+    slow: f32,
+    fast_coeff: f32,
+    slow_coeff: f32,
+    threshold: f32, // the energy ratio of a transient
+    holdoff_samples: usize,
+    holdoff: usize, // samples until new transients can be detected
+}
+
+fn coeff(sample_rate: usize, seconds: f64) -> f32 {
+    (1.0 - f64::exp(-1.0 / (seconds * sample_rate as f64))) as f32
+}
+
+impl TransientDetector {
+    pub fn new(sample_rate: usize, threshold_db: f64) -> TransientDetector {
+        let mut detector = TransientDetector {
+            previous: 0.0,
+            fast: 0.0,
+            slow: 0.0,
+            fast_coeff: coeff(sample_rate, FAST_SECONDS),
+            slow_coeff: coeff(sample_rate, SLOW_SECONDS),
+            threshold: 0.0,
+            holdoff_samples: (HOLDOFF_SECONDS * sample_rate as f64) as usize,
+            holdoff: 0,
+        };
+        detector.set_threshold(threshold_db);
+        detector
+    }
+
+    // How many dB above the background the high frequency energy needs to rise
+    pub fn set_threshold(&mut self, threshold_db: f64) {
+        self.threshold = 10.0f64.powf(threshold_db / 10.0) as f32;
+    }
+
+    // Returns true when a transient is detected at this sample
+    pub fn sample(&mut self, sample: f32) -> bool {
+        let high = sample - self.previous;
+        self.previous = sample;
+        let energy = high * high;
+        self.fast += (energy - self.fast) * self.fast_coeff;
+        self.slow += (energy - self.slow) * self.slow_coeff;
+
+        if self.holdoff > 0 {
+            self.holdoff -= 1;
+            false
+        } else if self.fast > MIN_ENERGY && self.fast > self.slow * self.threshold {
+            self.holdoff = self.holdoff_samples;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// The length of the frames the level is followed in
+const FRAME_SECONDS: f64 = 0.01;
+
+// The noise floor follows quieter frames with this time constant, and rises at most this fast,
+// so that a new noise source, such as a fan, is taken into account in some seconds, but speech
+// isn't
+const FLOOR_FALL_SECONDS: f64 = 0.05;
+const FLOOR_RISE_DB_PER_SECOND: f64 = 1.0;
+
+// Frames louder than the noise floor by this much are speech
+const SPEECH_DB: f64 = 9.0;
+
+// Frames quieter than this (-100 dBFS), such as the silence the delay starts with, are not
+// followed, as the noise floor could only rise back up from them slowly
+const MIN_RMS: f32 = 1e-5;
+
+// How long speech is considered to go on after the last loud frame, to cover the gaps between
+// words
+const HANGOVER_SECONDS: f64 = 0.3;
+
+fn db_to_gain(db: f64) -> f32 {
+    10.0f64.powf(db / 20.0) as f32
+}
+
+// VoiceActivity keeps track of the noise floor of the input and detects speech, or other
+// activity, that rises above it
+pub struct VoiceActivity {
+    frame_size: usize,
+    frame_energy: f32, // sum of the squares so far in the current frame
+    frame_position: usize,
+
+    floor: Option<f32>, // the RMS level of the noise floor, once there is one
+    floor_fall_coeff: f32,
+    floor_rise_per_frame: f32,
+    speech_ratio: f32,
+
+    hangover_frames: usize,
+    hangover: usize, // frames until the speech is over
+}
+
+impl VoiceActivity {
+    pub fn new(sample_rate: usize) -> VoiceActivity {
+        let frame_size = usize::max(1, (FRAME_SECONDS * sample_rate as f64) as usize);
+        let frame_seconds = frame_size as f64 / sample_rate as f64;
+        VoiceActivity {
+            frame_size,
+            frame_energy: 0.0,
+            frame_position: 0,
+            floor: None,
+            floor_fall_coeff: (1.0 - f64::exp(-frame_seconds / FLOOR_FALL_SECONDS)) as f32,
+            floor_rise_per_frame: db_to_gain(FLOOR_RISE_DB_PER_SECOND * frame_seconds),
+            speech_ratio: db_to_gain(SPEECH_DB),
+            hangover_frames: (HANGOVER_SECONDS / frame_seconds) as usize,
+            hangover: 0,
+        }
+    }
+
+    /** Follows a frame of audio, with a sample for each channel. With learn false, such as during
+     * the clicks, the noise floor is kept as it is. */
+    pub fn sample(&mut self, frame: &[f32], learn: bool) {
+        self.frame_energy += frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32;
+        self.frame_position += 1;
+        if self.frame_position < self.frame_size {
+            return;
+        }
+        let rms = f32::sqrt(self.frame_energy / self.frame_size as f32);
+        self.frame_energy = 0.0;
+        self.frame_position = 0;
+
+        match self.floor {
+            Some(floor) if rms > floor * self.speech_ratio => self.hangover = self.hangover_frames,
+            _ => self.hangover = self.hangover.saturating_sub(1),
+        }
+        if learn && rms > MIN_RMS {
+            self.floor = Some(match self.floor {
+                None => rms,
+                Some(floor) if rms < floor => floor + (rms - floor) * self.floor_fall_coeff,
+                Some(floor) => f32::min(rms, floor * self.floor_rise_per_frame),
+            });
+        }
+    }
+
+    pub fn is_speech(&self) -> bool {
+        self.hangover > 0
+    }
+
+    pub fn noise_floor(&self) -> Option<f32> {
+        self.floor
+    }
+}